@@ -1,13 +1,18 @@
 //! This module defines utilites for encoding buffers, which target better
 //! performances by not discarding allocations.
 
-use crate::{encode_into, error::Error};
-use std::cell::Cell;
+use crate::{codec::Compression, encode_into, error::Error};
+use std::{
+    cell::Cell,
+    mem,
+    sync::{Arc, Mutex},
+};
 
 /// An encode buffer. Useful for not throwing away allocations.
 #[derive(Debug, Default)]
 pub struct Buffer {
     bytes: Vec<u8>,
+    scratch: Vec<u8>,
 }
 
 impl Buffer {
@@ -21,6 +26,25 @@ impl Buffer {
         Ok(&self.bytes)
     }
 
+    /// Encodes the given input data, and compresses the encoded bytes with the
+    /// given settings, if any. Encryption and checksums are applied only when
+    /// values are stored, since they depend on where values are stored.
+    pub fn encode_with<T>(
+        &mut self,
+        data: T,
+        compression: Option<&Compression>,
+    ) -> Result<&[u8], Error>
+    where
+        T: serde::Serialize,
+    {
+        self.encode(data)?;
+        if let Some(compression) = compression {
+            compression.compress(&self.bytes, &mut self.scratch)?;
+            mem::swap(&mut self.bytes, &mut self.scratch);
+        }
+        Ok(&self.bytes)
+    }
+
     /// Returns the last encoded bytes. Initially, this is just an empty slice.
     pub fn bytes(&self) -> &[u8] {
         &self.bytes
@@ -50,7 +74,7 @@ pub trait Allocation {
     fn free(&mut self);
 }
 
-impl<'this, A> Allocation for &'this mut A
+impl<A> Allocation for &mut A
 where
     A: Allocation,
 {
@@ -67,6 +91,45 @@ where
     }
 }
 
+/// A key buffer and a value buffer made by an allocation, which are saved back
+/// into it when the guard is dropped, so they are not lost if the future using
+/// them is cancelled.
+pub(crate) struct Guard<'alloc, A>
+where
+    A: Allocation,
+{
+    allocation: &'alloc mut A,
+    buffers: [Buffer; 2],
+}
+
+impl<'alloc, A> Guard<'alloc, A>
+where
+    A: Allocation,
+{
+    /// Makes the buffers with the given allocation.
+    pub(crate) fn new(allocation: &'alloc mut A) -> Self {
+        let buffers = [allocation.make(), allocation.make()];
+        Self { allocation, buffers }
+    }
+
+    /// Returns the key buffer and the value buffer.
+    pub(crate) fn pair(&mut self) -> (&mut Buffer, &mut Buffer) {
+        let [key_buf, val_buf] = &mut self.buffers;
+        (key_buf, val_buf)
+    }
+}
+
+impl<'alloc, A> Drop for Guard<'alloc, A>
+where
+    A: Allocation,
+{
+    fn drop(&mut self) {
+        for buffer in &mut self.buffers {
+            self.allocation.save(mem::take(buffer));
+        }
+    }
+}
+
 /// Allocator for one time use buffers. Saving buffers through this does
 /// nothing.
 #[derive(Debug, Clone, Default)]
@@ -90,7 +153,7 @@ pub struct Pool {
 
 impl Allocation for Pool {
     fn make(&mut self) -> Buffer {
-        self.buffers.pop().unwrap_or_else(Buffer::default)
+        self.buffers.pop().unwrap_or_default()
     }
 
     fn save(&mut self, buffer: Buffer) {
@@ -142,3 +205,37 @@ impl Allocation for DefaultPool {
         with_default_pool(Pool::free)
     }
 }
+
+/// A pool for buffer allocations shared by its clones, e.g. by the clones of a
+/// tree using it as its allocation strategy. Saves all allocated buffers.
+#[derive(Debug, Clone, Default)]
+pub struct SharedPool {
+    pool: Arc<Mutex<Pool>>,
+}
+
+impl SharedPool {
+    fn with_pool<F, T>(&self, visitor: F) -> T
+    where
+        F: FnOnce(&mut Pool) -> T,
+    {
+        let mut pool = match self.pool.lock() {
+            Ok(pool) => pool,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        visitor(&mut pool)
+    }
+}
+
+impl Allocation for SharedPool {
+    fn make(&mut self) -> Buffer {
+        self.with_pool(Pool::make)
+    }
+
+    fn save(&mut self, buffer: Buffer) {
+        self.with_pool(|pool| pool.save(buffer))
+    }
+
+    fn free(&mut self) {
+        self.with_pool(Pool::free)
+    }
+}
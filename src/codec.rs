@@ -0,0 +1,780 @@
+//! This module defines codecs, which transform encoded values before they are
+//! stored, and transform them back before they are decoded. Keys are never
+//! transformed, so their order and lookups are not affected.
+
+#[cfg(feature = "chacha20poly1305")]
+mod encryption;
+
+#[cfg(feature = "chacha20poly1305")]
+pub use self::encryption::{Encryption, Key};
+
+#[cfg(not(all(feature = "zstd", feature = "lz4")))]
+use crate::error::Unsupported;
+use crate::{
+    error::{Corruption, Error, ErrorKind},
+    DECODE_LIMIT,
+};
+use std::{borrow::Cow, io, sync::atomic::Ordering};
+
+/// Header of a value stored without compression.
+const UNCOMPRESSED: u8 = 0;
+
+/// Header of a value compressed with zstd.
+const ZSTD: u8 = 1;
+
+/// Header of a value compressed with lz4.
+const LZ4: u8 = 2;
+
+fn compression_error<E>(error: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let error = io::Error::new(io::ErrorKind::InvalidData, error);
+    Error::new(ErrorKind::Compression(error))
+}
+
+/// Splits stored bytes into the header of the given size and the rest.
+fn split_header(
+    stored: &[u8],
+    header_size: usize,
+) -> Result<(&[u8], &[u8]), Error> {
+    if stored.len() < header_size {
+        let error = io::Error::from(io::ErrorKind::UnexpectedEof);
+        return Err(bincode::Error::from(error).into());
+    }
+    Ok(stored.split_at(header_size))
+}
+
+/// Where a value is stored: the tree and the encoded key. Encryption
+/// authenticates it along with the value, so a value copied to another key or
+/// tree cannot be decrypted.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "chacha20poly1305"), allow(dead_code))]
+pub(crate) struct Context<'ctx> {
+    tree: &'ctx sled::Tree,
+    key: &'ctx [u8],
+}
+
+impl<'ctx> Context<'ctx> {
+    /// Creates the context of a value stored in `tree` under the given encoded
+    /// key.
+    pub(crate) fn new(tree: &'ctx sled::Tree, key: &'ctx [u8]) -> Self {
+        Self { tree, key }
+    }
+
+    /// Returns the data authenticated along with a value stored with the given
+    /// header: the tree name and the key, each prefixed by its length as big
+    /// endian `u64`, followed by the header.
+    #[cfg(feature = "chacha20poly1305")]
+    fn associated_data(&self, header: &[u8]) -> Vec<u8> {
+        let name = self.tree.name();
+        let size = 16 + name.len() + self.key.len() + header.len();
+        let mut data = Vec::with_capacity(size);
+        for part in [&name[..], self.key] {
+            data.extend_from_slice(&(part.len() as u64).to_be_bytes());
+            data.extend_from_slice(part);
+        }
+        data.extend_from_slice(header);
+        data
+    }
+}
+
+/// A compression algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Algorithm {
+    /// No compression. Values are still stored with a compression header, so
+    /// an algorithm can be configured later without breaking existing data.
+    Uncompressed,
+    /// Zstandard compression, with the given level.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+    /// LZ4 block compression.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// Compression settings of encoded values.
+///
+/// Every value of a tree with compression settings is stored with a header
+/// byte recording how it was compressed, so the algorithm and threshold can be
+/// changed without breaking existing data, as long as the features for the
+/// algorithms used in existing data are enabled. Use
+/// [`Algorithm::Uncompressed`] to stop compressing new values. A tree without
+/// compression settings stores values without the header, in the same format
+/// as before compression was supported, so compression settings cannot be
+/// added to, or removed from, a tree with existing data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    algorithm: Algorithm,
+    threshold: usize,
+}
+
+impl Compression {
+    /// Default size, in bytes, below which values are stored uncompressed.
+    pub const DEFAULT_THRESHOLD: usize = 128;
+
+    /// Creates compression settings with the given algorithm and the default
+    /// threshold.
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self { algorithm, threshold: Self::DEFAULT_THRESHOLD }
+    }
+
+    /// Sets the size, in bytes, below which encoded values are stored
+    /// uncompressed.
+    pub fn threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Compresses the given input into the output, including the header. If
+    /// compression does not make the value smaller, it is stored uncompressed.
+    pub(crate) fn compress(
+        &self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        output.clear();
+        if input.len() >= self.threshold {
+            match self.algorithm {
+                Algorithm::Uncompressed => (),
+                #[cfg(feature = "zstd")]
+                Algorithm::Zstd(level) => {
+                    output.push(ZSTD);
+                    zstd::stream::copy_encode(input, &mut *output, level)
+                        .map_err(|error| {
+                            Error::new(ErrorKind::Compression(error))
+                        })?;
+                },
+                #[cfg(feature = "lz4")]
+                Algorithm::Lz4 => {
+                    if input.len() > u32::MAX as usize {
+                        return Err(compression_error(
+                            "value too large for lz4",
+                        ));
+                    }
+                    let size = input.len() as u32;
+                    output.push(LZ4);
+                    output.extend_from_slice(&size.to_le_bytes());
+                    let start = output.len();
+                    let max_size =
+                        lz4_flex::block::get_maximum_output_size(input.len());
+                    output.resize(start + max_size, 0);
+                    let written = lz4_flex::block::compress_into(
+                        input,
+                        &mut output[start..],
+                    )
+                    .map_err(compression_error)?;
+                    output.truncate(start + written);
+                },
+            }
+        }
+
+        if output.is_empty() || output.len() > input.len() {
+            output.clear();
+            output.push(UNCOMPRESSED);
+            output.extend_from_slice(input);
+        }
+        Ok(())
+    }
+
+    /// Decompresses the given input, including the header, failing if the
+    /// output would exceed `limit` bytes.
+    pub(crate) fn decompress(
+        input: &[u8],
+        limit: u64,
+    ) -> Result<Cow<'_, [u8]>, Error> {
+        let (header, payload) = input
+            .split_first()
+            .ok_or_else(|| compression_error("missing compression header"))?;
+        match *header {
+            UNCOMPRESSED => Ok(Cow::Borrowed(payload)),
+            ZSTD => Self::decompress_zstd(payload, limit).map(Cow::Owned),
+            LZ4 => Self::decompress_lz4(payload, limit).map(Cow::Owned),
+            _ => Err(compression_error("unknown compression header")),
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    fn decompress_zstd(payload: &[u8], limit: u64) -> Result<Vec<u8>, Error> {
+        use std::io::Read;
+
+        let compression = |error| Error::new(ErrorKind::Compression(error));
+        let decoder =
+            zstd::stream::read::Decoder::new(payload).map_err(compression)?;
+        let mut output = Vec::new();
+        decoder
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut output)
+            .map_err(compression)?;
+        if output.len() as u64 > limit {
+            return Err(crate::error::LimitExceeded::new(limit).into());
+        }
+        Ok(output)
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    fn decompress_zstd(_payload: &[u8], _limit: u64) -> Result<Vec<u8>, Error> {
+        Err(Unsupported::new("decompressing zstd values", "the zstd feature")
+            .into())
+    }
+
+    #[cfg(feature = "lz4")]
+    fn decompress_lz4(payload: &[u8], limit: u64) -> Result<Vec<u8>, Error> {
+        if payload.len() < 4 {
+            return Err(compression_error("missing lz4 size"));
+        }
+        let (size, compressed) = payload.split_at(4);
+        let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]);
+        // A byte of an lz4 block expands into at most 255 bytes, so a larger
+        // declared size is corrupted and must not be allocated.
+        if u64::from(size) > compressed.len() as u64 * 255 {
+            return Err(compression_error("lz4 size larger than possible"));
+        }
+        if u64::from(size) > limit {
+            return Err(crate::error::LimitExceeded::new(limit).into());
+        }
+        lz4_flex::block::decompress(compressed, size as usize)
+            .map_err(compression_error)
+    }
+
+    #[cfg(not(feature = "lz4"))]
+    fn decompress_lz4(_payload: &[u8], _limit: u64) -> Result<Vec<u8>, Error> {
+        Err(Unsupported::new("decompressing lz4 values", "the lz4 feature")
+            .into())
+    }
+}
+
+/// A checksum algorithm, used to detect corrupted values.
+///
+/// The checksum of a value is appended to it after every other transformation,
+/// covering every stored byte, including any header stored before the value,
+/// such as the envelope, and checked before every read, so a corrupted value
+/// produces [`ErrorKind::Corruption`] instead of being decoded. The checksum is
+/// not tagged with its algorithm, so a tree must always be opened with the same
+/// checksum settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Checksum {
+    /// CRC-32C (Castagnoli), stored as 4 bytes.
+    #[cfg(feature = "crc32c")]
+    Crc32c,
+    /// 64-bit XXH3, stored as 8 bytes.
+    #[cfg(feature = "xxhash")]
+    Xxh3,
+}
+
+impl Checksum {
+    /// Appends the checksum of the given bytes to them.
+    #[cfg_attr(
+        not(any(feature = "crc32c", feature = "xxhash")),
+        allow(unused_variables, clippy::ptr_arg)
+    )]
+    fn append(&self, bytes: &mut Vec<u8>) {
+        match *self {
+            #[cfg(feature = "crc32c")]
+            Checksum::Crc32c => {
+                let sum = crc32c::crc32c(bytes);
+                bytes.extend_from_slice(&sum.to_be_bytes());
+            },
+            #[cfg(feature = "xxhash")]
+            Checksum::Xxh3 => {
+                let sum = xxhash_rust::xxh3::xxh3_64(bytes);
+                bytes.extend_from_slice(&sum.to_be_bytes());
+            },
+        }
+    }
+
+    /// Checks the checksum appended to the given bytes, returning the bytes
+    /// without it, or `None` if they do not match.
+    #[cfg_attr(
+        not(any(feature = "crc32c", feature = "xxhash")),
+        allow(unused_variables)
+    )]
+    fn verify<'bytes>(&self, bytes: &'bytes [u8]) -> Option<&'bytes [u8]> {
+        let split = |size: usize| {
+            let at = bytes.len().checked_sub(size)?;
+            Some(bytes.split_at(at))
+        };
+        match *self {
+            #[cfg(feature = "crc32c")]
+            Checksum::Crc32c => {
+                let (payload, sum) = split(4)?;
+                let valid = crc32c::crc32c(payload).to_be_bytes() == sum;
+                if valid {
+                    Some(payload)
+                } else {
+                    None
+                }
+            },
+            #[cfg(feature = "xxhash")]
+            Checksum::Xxh3 => {
+                let (payload, sum) = split(8)?;
+                let valid =
+                    xxhash_rust::xxh3::xxh3_64(payload).to_be_bytes() == sum;
+                if valid {
+                    Some(payload)
+                } else {
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// A codec for encoded values. By default, it does not transform values at
+/// all, so they are stored as they are encoded.
+///
+/// Values are compressed when they are encoded, and encrypted when they are
+/// stored, since encryption authenticates the tree and the key under which
+/// each value is stored, along with any header stored before the value, such
+/// as the envelope. Values are decrypted before they are decompressed.
+#[derive(Debug, Clone, Default)]
+pub struct Codec {
+    compression: Option<Compression>,
+    checksum: Option<Checksum>,
+    limit: Option<u64>,
+    #[cfg(feature = "chacha20poly1305")]
+    encryption: Option<Encryption>,
+}
+
+impl Codec {
+    /// Creates a codec which does not compress, encrypt or checksum values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enables compression of values with the given settings.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Sets the maximum size, in bytes, of encoded values to decode, and of
+    /// their decompressed values, overriding the limit set by
+    /// [`set_decode_limit`](crate::set_decode_limit).
+    pub fn decode_limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Returns the maximum size, in bytes, of decoded values.
+    pub(crate) fn limit(&self) -> u64 {
+        match self.limit {
+            Some(limit) => limit,
+            None => DECODE_LIMIT.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Enables checksums of values with the given algorithm.
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// Enables encryption of values with the given settings.
+    #[cfg(feature = "chacha20poly1305")]
+    pub fn encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Tests whether values are encrypted.
+    pub(crate) fn is_encrypted(&self) -> bool {
+        #[cfg(feature = "chacha20poly1305")]
+        {
+            self.encryption.is_some()
+        }
+        #[cfg(not(feature = "chacha20poly1305"))]
+        {
+            false
+        }
+    }
+
+    /// Re-encrypts the given stored bytes, whose first `header_size` bytes are
+    /// the header they were sealed with, with the current key, returning
+    /// `None` if they are already encrypted with it, or if values are not
+    /// encrypted.
+    #[cfg(feature = "chacha20poly1305")]
+    pub(crate) fn reencrypt(
+        &self,
+        context: Context,
+        stored: &[u8],
+        header_size: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let encryption = match &self.encryption {
+            Some(encryption) => encryption,
+            None => return Ok(None),
+        };
+        let (header, body) = split_header(self.verify(stored)?, header_size)?;
+        if encryption.uses_current_key(body)? {
+            return Ok(None);
+        }
+        let payload =
+            encryption.decrypt(body, &context.associated_data(header))?;
+        Ok(Some(self.seal(context, header, &payload)?.into_owned()))
+    }
+
+    /// Checks the checksum of the given stored bytes, if enabled, returning
+    /// the bytes without it.
+    fn verify<'bytes>(
+        &self,
+        bytes: &'bytes [u8],
+    ) -> Result<&'bytes [u8], Error> {
+        match &self.checksum {
+            Some(checksum) => Ok(checksum
+                .verify(bytes)
+                .ok_or_else(|| Corruption::new(&[], &[]))?),
+            None => Ok(bytes),
+        }
+    }
+
+    /// Returns the compression settings, if values are compressed.
+    pub(crate) fn compression_settings(&self) -> Option<&Compression> {
+        self.compression.as_ref()
+    }
+
+    /// Decompresses a payload back into encoded bytes.
+    pub(crate) fn decompress<'payload>(
+        &self,
+        payload: &'payload [u8],
+    ) -> Result<Cow<'payload, [u8]>, Error> {
+        match &self.compression {
+            Some(_) => Compression::decompress(payload, self.limit()),
+            None => Ok(Cow::Borrowed(payload)),
+        }
+    }
+
+    /// Makes stored bytes from a header, stored in the clear, and a payload,
+    /// which is encrypted if enabled, authenticating the context and the
+    /// header along with it. The checksum, if enabled, is appended. Shares the
+    /// payload if nothing is added to it.
+    pub(crate) fn seal<'payload>(
+        &self,
+        context: Context,
+        header: &[u8],
+        payload: &'payload [u8],
+    ) -> Result<Cow<'payload, [u8]>, Error> {
+        if header.is_empty() && self.checksum.is_none() && !self.is_encrypted()
+        {
+            return Ok(Cow::Borrowed(payload));
+        }
+        let mut stored = Vec::with_capacity(header.len() + payload.len());
+        stored.extend_from_slice(header);
+        self.encrypt_into(context, header, payload, &mut stored)?;
+        if let Some(checksum) = &self.checksum {
+            checksum.append(&mut stored);
+        }
+        Ok(Cow::Owned(stored))
+    }
+
+    /// Appends the given payload to the stored bytes, encrypted if enabled.
+    #[cfg_attr(
+        not(feature = "chacha20poly1305"),
+        allow(unused_variables, clippy::unnecessary_wraps)
+    )]
+    fn encrypt_into(
+        &self,
+        context: Context,
+        header: &[u8],
+        payload: &[u8],
+        stored: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        #[cfg(feature = "chacha20poly1305")]
+        if let Some(encryption) = &self.encryption {
+            let associated_data = context.associated_data(header);
+            return encryption.encrypt(payload, &associated_data, stored);
+        }
+        stored.extend_from_slice(payload);
+        Ok(())
+    }
+
+    /// Checks and decrypts stored bytes, whose first `header_size` bytes are
+    /// the header they were sealed with, returning the payload, still
+    /// compressed.
+    #[cfg_attr(not(feature = "chacha20poly1305"), allow(unused_variables))]
+    pub(crate) fn open_payload<'bytes>(
+        &self,
+        context: Context,
+        stored: &'bytes [u8],
+        header_size: usize,
+    ) -> Result<Cow<'bytes, [u8]>, Error> {
+        let (header, body) = split_header(self.verify(stored)?, header_size)?;
+        #[cfg(feature = "chacha20poly1305")]
+        if let Some(encryption) = &self.encryption {
+            let associated_data = context.associated_data(header);
+            return Ok(Cow::Owned(encryption.decrypt(body, &associated_data)?));
+        }
+        Ok(Cow::Borrowed(body))
+    }
+
+    /// Checks, decrypts and decompresses stored bytes, whose first
+    /// `header_size` bytes are the header they were sealed with, returning the
+    /// encoded bytes.
+    pub(crate) fn open<'bytes>(
+        &self,
+        context: Context,
+        stored: &'bytes [u8],
+        header_size: usize,
+    ) -> Result<Cow<'bytes, [u8]>, Error> {
+        match self.open_payload(context, stored, header_size)? {
+            Cow::Borrowed(payload) => self.decompress(payload),
+            Cow::Owned(payload) => {
+                Ok(Cow::Owned(self.decompress(&payload)?.into_owned()))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "chacha20poly1305")]
+    use super::Encryption;
+    use super::{Algorithm, Checksum, Codec, Compression, Context};
+    use crate::{
+        buffer::Buffer,
+        tree::{OpenOptions, Tree},
+    };
+
+    fn algorithms() -> Vec<Algorithm> {
+        vec![
+            Algorithm::Uncompressed,
+            #[cfg(feature = "zstd")]
+            Algorithm::Zstd(3),
+            #[cfg(feature = "lz4")]
+            Algorithm::Lz4,
+        ]
+    }
+
+    fn checksums() -> Vec<Option<Checksum>> {
+        vec![
+            None,
+            #[cfg(feature = "crc32c")]
+            Some(Checksum::Crc32c),
+            #[cfg(feature = "xxhash")]
+            Some(Checksum::Xxh3),
+        ]
+    }
+
+    /// Returns every combination of the settings of a codec.
+    fn codecs() -> Vec<Codec> {
+        let mut compressions = vec![None];
+        for algorithm in algorithms() {
+            let compression = Compression::new(algorithm).threshold(0);
+            compressions.push(Some(compression));
+        }
+
+        let mut codecs = Vec::new();
+        for compression in &compressions {
+            for checksum in checksums() {
+                let mut codec = Codec::new();
+                if let Some(compression) = compression {
+                    codec = codec.compression(*compression);
+                }
+                if let Some(checksum) = checksum {
+                    codec = codec.checksum(checksum);
+                }
+                #[cfg(feature = "chacha20poly1305")]
+                {
+                    let encryption = Encryption::new(1, &[7; 32]);
+                    codecs.push(codec.clone().encryption(encryption));
+                }
+                codecs.push(codec);
+            }
+        }
+        codecs
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("tree").unwrap();
+        let values = [String::new(), "a".repeat(1000), "abc".repeat(100)];
+        let headers: [&[u8]; 2] = [&[], &[1; 25]];
+
+        for codec in codecs() {
+            for (index, val) in values.iter().enumerate() {
+                let key = [index as u8];
+                let context = Context::new(&tree, &key);
+                let mut buffer = Buffer::default();
+                let compression = codec.compression_settings();
+                let encoded = buffer.encode_with(val, compression).unwrap();
+                for header in headers {
+                    let stored = codec.seal(context, header, encoded).unwrap();
+                    assert_eq!(&stored[..header.len()], header);
+                    let opened =
+                        codec.open(context, &stored, header.len()).unwrap();
+                    let decoded: String = crate::decode(&opened).unwrap();
+                    assert_eq!(&decoded, val, "{:?}", codec);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_with_decode_with_round_trip() {
+        let value = "abc".repeat(100);
+        let mut compressions = vec![None];
+        for algorithm in algorithms() {
+            compressions.push(Some(Compression::new(algorithm).threshold(0)));
+        }
+        for compression in &compressions {
+            let mut buffer = Buffer::default();
+            let encoded =
+                buffer.encode_with(&value, compression.as_ref()).unwrap();
+            let decoded: String =
+                crate::decode_with(encoded, compression.as_ref()).unwrap();
+            assert_eq!(decoded, value, "{:?}", compression);
+        }
+    }
+
+    #[cfg(feature = "chacha20poly1305")]
+    #[test]
+    fn encryption_authenticates_context() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("tree").unwrap();
+        let other_tree = db.open_tree("other").unwrap();
+        let codec = Codec::new().encryption(Encryption::new(1, &[7; 32]));
+        let context = Context::new(&tree, b"key");
+        let stored = codec.seal(context, &[1; 8], b"\0value").unwrap();
+
+        assert!(codec.open(context, &stored, 8).is_ok());
+        let mut tampered = stored.to_vec();
+        tampered[0] = 2;
+        assert!(codec.open(context, &tampered, 8).is_err());
+        let other_key = Context::new(&tree, b"other key");
+        assert!(codec.open(other_key, &stored, 8).is_err());
+        let other_tree = Context::new(&other_tree, b"key");
+        assert!(codec.open(other_tree, &stored, 8).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn read_baseline_values() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let storage = db.open_tree("tree").unwrap();
+        let values = [String::new(), "a".repeat(1000), "b".to_owned()];
+        for (key, val) in values.iter().enumerate() {
+            let key = crate::encode(key as u64).unwrap();
+            storage.insert(key, crate::encode(val).unwrap()).unwrap();
+        }
+
+        let tree = Tree::<u64, String>::open(&db, "tree").await.unwrap();
+        for (key, val) in values.iter().enumerate() {
+            let stored = tree.get(&(key as u64)).await.unwrap();
+            assert_eq!(stored.as_ref(), Some(val));
+        }
+        let key = values.len() as u64;
+        tree.insert(&key, &"c".to_owned()).await.unwrap();
+        let stored = storage.get(crate::encode(key).unwrap()).unwrap();
+        assert_eq!(stored.unwrap(), crate::encode("c").unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn change_compression_of_existing_data() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let values = [String::new(), "a".repeat(1000), "b".to_owned()];
+        let uncompressed = Compression::new(Algorithm::Uncompressed);
+        let options = OpenOptions::new().compression(uncompressed);
+
+        for algorithm in algorithms() {
+            let name = format!("{:?}", algorithm);
+            let tree = Tree::<u64, String>::open_with(&db, &name, &options)
+                .await
+                .unwrap();
+            for (key, val) in values.iter().enumerate() {
+                tree.insert(&(key as u64), val).await.unwrap();
+            }
+
+            let compression = Compression::new(algorithm).threshold(0);
+            let options = OpenOptions::new().compression(compression);
+            let tree = Tree::<u64, String>::open_with(&db, &name, &options)
+                .await
+                .unwrap();
+            for (key, val) in values.iter().enumerate() {
+                let stored = tree.get(&(key as u64)).await.unwrap();
+                assert_eq!(stored.as_ref(), Some(val));
+            }
+            let key = values.len() as u64;
+            tree.insert(&key, &"c".repeat(1000)).await.unwrap();
+
+            let tree = Tree::<u64, String>::open_with(&db, &name, &options)
+                .await
+                .unwrap();
+            let stored = tree.get(&key).await.unwrap();
+            assert_eq!(stored, Some("c".repeat(1000)));
+        }
+    }
+
+    #[cfg(feature = "chacha20poly1305")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn swapped_values_fail_to_decrypt() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let encryption = Encryption::new(1, &[7; 32]);
+        let options = OpenOptions::new().envelope(true).encryption(encryption);
+        let tree = Tree::<u64, String>::open_with(&db, "tree", &options)
+            .await
+            .unwrap();
+        tree.insert(&1, &"one".to_owned()).await.unwrap();
+        tree.insert(&2, &"two".to_owned()).await.unwrap();
+
+        let storage = db.open_tree("tree").unwrap();
+        let one = crate::encode(1u64).unwrap();
+        let two = crate::encode(2u64).unwrap();
+        let stored_one = storage.get(&one).unwrap().unwrap();
+        let stored_two = storage.get(&two).unwrap().unwrap();
+        storage.insert(&one, stored_two).unwrap();
+        storage.insert(&two, stored_one).unwrap();
+
+        assert!(tree.get(&1).await.is_err());
+        assert!(tree.get(&2).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decode_limit_is_enforced() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        for algorithm in algorithms() {
+            let name = format!("{:?}", algorithm);
+            let compression = Compression::new(algorithm).threshold(0);
+            let options =
+                OpenOptions::new().compression(compression).decode_limit(100);
+            let tree = Tree::<u64, String>::open_with(&db, &name, &options)
+                .await
+                .unwrap();
+            tree.insert(&1, &"a".repeat(10)).await.unwrap();
+            tree.insert(&2, &"a".repeat(1000)).await.unwrap();
+
+            let stored = tree.get(&1).await.unwrap();
+            assert_eq!(stored, Some("a".repeat(10)));
+            let error = tree.get(&2).await.unwrap_err();
+            assert!(error.is_limit_exceeded(), "{:?}: {}", algorithm, error);
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_size_is_bounded_by_input() {
+        let mut input = vec![super::LZ4];
+        input.extend_from_slice(&u32::MAX.to_le_bytes());
+        input.extend_from_slice(&[0x10, b'a']);
+        let error = Compression::decompress(&input, u64::MAX).unwrap_err();
+        assert!(!error.is_limit_exceeded());
+    }
+
+    #[cfg(any(feature = "crc32c", feature = "xxhash"))]
+    #[test]
+    fn checksum_covers_header() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("tree").unwrap();
+        let context = Context::new(&tree, b"key");
+        for checksum in checksums().into_iter().flatten() {
+            let codec = Codec::new().checksum(checksum);
+            let stored = codec.seal(context, &[1; 8], b"\0value").unwrap();
+            assert!(codec.open(context, &stored, 8).is_ok());
+            for index in 0..stored.len() {
+                let mut corrupted = stored.to_vec();
+                corrupted[index] ^= 1;
+                assert!(codec.open(context, &corrupted, 8).is_err());
+            }
+        }
+    }
+}
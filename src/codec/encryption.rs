@@ -0,0 +1,150 @@
+//! Encryption of stored values with ChaCha20-Poly1305.
+//!
+//! An encrypted value is the identifier of the key it was encrypted with,
+//! encoded as big endian `u32`, followed by the nonce, the ciphertext and the
+//! authentication tag. The tree and the key under which the value is stored,
+//! and any header stored before it, are authenticated as associated data, so a
+//! value swapped with the value of another key fails to decrypt.
+
+use crate::error::{AuthenticationError, Error, ErrorKind};
+use chacha20poly1305::{
+    aead::{AeadCore, AeadInPlace, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce, Tag,
+};
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+/// Size of the key identifier header.
+const KEY_ID_SIZE: usize = 4;
+
+/// Size of a nonce.
+const NONCE_SIZE: usize = 12;
+
+/// Size of an authentication tag.
+const TAG_SIZE: usize = 16;
+
+/// A 256-bit encryption key.
+pub type Key = [u8; 32];
+
+#[derive(Clone)]
+struct Keyring {
+    current: u32,
+    ciphers: BTreeMap<u32, ChaCha20Poly1305>,
+}
+
+/// Encryption settings of stored values.
+///
+/// Values are encrypted with the current key, and its identifier is stored
+/// along with every value, so keys can be rotated: values encrypted with any
+/// key registered through [`Encryption::decryption_key`] can still be read, and
+/// [`Tree::reencrypt`](crate::tree::Tree::reencrypt) re-encrypts them with the
+/// current key. Keys are never stored.
+#[derive(Clone)]
+pub struct Encryption {
+    keyring: Arc<Keyring>,
+}
+
+impl Encryption {
+    /// Creates encryption settings using the given key, identified by
+    /// `key_id`, to encrypt values.
+    pub fn new(key_id: u32, key: &Key) -> Self {
+        let mut ciphers = BTreeMap::new();
+        ciphers.insert(key_id, ChaCha20Poly1305::new(key.into()));
+        Self { keyring: Arc::new(Keyring { current: key_id, ciphers }) }
+    }
+
+    /// Registers a key, identified by `key_id`, which is only used to decrypt
+    /// values, such as an old key being rotated out. The current key cannot be
+    /// replaced.
+    pub fn decryption_key(mut self, key_id: u32, key: &Key) -> Self {
+        let keyring = Arc::make_mut(&mut self.keyring);
+        if key_id != keyring.current {
+            keyring.ciphers.insert(key_id, ChaCha20Poly1305::new(key.into()));
+        }
+        self
+    }
+
+    /// Returns the identifier of the key used to encrypt values.
+    pub fn key_id(&self) -> u32 {
+        self.keyring.current
+    }
+
+    /// Encrypts the given input, authenticating `associated_data` along with
+    /// it, and appends it to the output, including the header.
+    pub(crate) fn encrypt(
+        &self,
+        input: &[u8],
+        associated_data: &[u8],
+        output: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let current = self.keyring.current;
+        let cipher = &self.keyring.ciphers[&current];
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        output.reserve(KEY_ID_SIZE + NONCE_SIZE + input.len() + TAG_SIZE);
+        output.extend_from_slice(&current.to_be_bytes());
+        output.extend_from_slice(&nonce);
+        let start = output.len();
+        output.extend_from_slice(input);
+        let tag = cipher
+            .encrypt_in_place_detached(
+                &nonce,
+                associated_data,
+                &mut output[start..],
+            )
+            .map_err(|error| Error::new(ErrorKind::Custom(Box::new(error))))?;
+        output.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Decrypts the given input, including the header, which must have been
+    /// encrypted with the same `associated_data`.
+    pub(crate) fn decrypt(
+        &self,
+        input: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let key_id = Self::stored_key_id(input)?;
+        let cipher = self
+            .keyring
+            .ciphers
+            .get(&key_id)
+            .ok_or_else(|| AuthenticationError::new(key_id, true))?;
+        let payload = &input[KEY_ID_SIZE..];
+        if payload.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(AuthenticationError::new(key_id, false).into());
+        }
+        let (nonce, payload) = payload.split_at(NONCE_SIZE);
+        let (ciphertext, tag) = payload.split_at(payload.len() - TAG_SIZE);
+        let mut output = ciphertext.to_vec();
+        cipher
+            .decrypt_in_place_detached(
+                Nonce::from_slice(nonce),
+                associated_data,
+                &mut output,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| AuthenticationError::new(key_id, false))?;
+        Ok(output)
+    }
+
+    /// Tests whether the given input, including the header, is encrypted with
+    /// the current key.
+    pub(crate) fn uses_current_key(&self, input: &[u8]) -> Result<bool, Error> {
+        Ok(Self::stored_key_id(input)? == self.keyring.current)
+    }
+
+    fn stored_key_id(input: &[u8]) -> Result<u32, Error> {
+        if input.len() < KEY_ID_SIZE {
+            return Err(AuthenticationError::new(0, false).into());
+        }
+        Ok(u32::from_be_bytes([input[0], input[1], input[2], input[3]]))
+    }
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Encryption")
+            .field("key_id", &self.keyring.current)
+            .field("key_ids", &self.keyring.ciphers.keys())
+            .finish()
+    }
+}
@@ -1,6 +1,7 @@
 //! Exports error types for this library.
 
-use std::{error::Error as ErrorTrait, fmt};
+use sled::{transaction::TransactionError, IVec};
+use std::{error::Error as ErrorTrait, fmt, io, time::Duration};
 
 /// The kind of an error that may happen handling storage.
 #[derive(Debug)]
@@ -10,6 +11,27 @@ pub enum ErrorKind {
     Sled(sled::Error),
     /// Serialization or deserialization error.
     Serde(bincode::Error),
+    /// Compression or decompression error.
+    Compression(io::Error),
+    /// An encrypted value could not be authenticated, and so it was not
+    /// decrypted.
+    Authentication(AuthenticationError),
+    /// A stored value does not match its checksum.
+    Corruption(Corruption),
+    /// Decoding needed more memory than the decode limit.
+    LimitExceeded(LimitExceeded),
+    /// A string is not a valid encoded ID.
+    InvalidId(InvalidId),
+    /// An ID generation did not complete within its timeout.
+    Timeout(Timeout),
+    /// An operation that the tree was not configured to support.
+    Unsupported(Unsupported),
+    /// A write conflicted with the current state of an entry, such as a
+    /// version mismatch, and may succeed if retried with fresh data.
+    Conflict(Box<dyn ErrorTrait + Send + Sync>),
+    /// A write violated a constraint on the data, and will not succeed if
+    /// retried as it is.
+    Constraint(Box<dyn ErrorTrait + Send + Sync>),
     /// A custom error, stored in a trait object.
     Custom(Box<dyn ErrorTrait + Send + Sync>),
 }
@@ -20,6 +42,15 @@ impl ErrorKind {
         match self {
             ErrorKind::Serde(error) => error,
             ErrorKind::Sled(error) => error,
+            ErrorKind::Compression(error) => error,
+            ErrorKind::Authentication(error) => error,
+            ErrorKind::Corruption(error) => error,
+            ErrorKind::LimitExceeded(error) => error,
+            ErrorKind::InvalidId(error) => error,
+            ErrorKind::Timeout(error) => error,
+            ErrorKind::Unsupported(error) => error,
+            ErrorKind::Conflict(error) => &**error,
+            ErrorKind::Constraint(error) => &**error,
             ErrorKind::Custom(error) => &**error,
         }
     }
@@ -37,38 +68,335 @@ impl From<sled::Error> for ErrorKind {
     }
 }
 
+impl From<AuthenticationError> for ErrorKind {
+    fn from(error: AuthenticationError) -> Self {
+        ErrorKind::Authentication(error)
+    }
+}
+
+impl From<Corruption> for ErrorKind {
+    fn from(error: Corruption) -> Self {
+        ErrorKind::Corruption(error)
+    }
+}
+
+impl From<LimitExceeded> for ErrorKind {
+    fn from(error: LimitExceeded) -> Self {
+        ErrorKind::LimitExceeded(error)
+    }
+}
+
+impl From<InvalidId> for ErrorKind {
+    fn from(error: InvalidId) -> Self {
+        ErrorKind::InvalidId(error)
+    }
+}
+
+impl From<Timeout> for ErrorKind {
+    fn from(error: Timeout) -> Self {
+        ErrorKind::Timeout(error)
+    }
+}
+
+impl From<Unsupported> for ErrorKind {
+    fn from(error: Unsupported) -> Self {
+        ErrorKind::Unsupported(error)
+    }
+}
+
 impl From<Box<dyn ErrorTrait + Send + Sync>> for ErrorKind {
     fn from(error: Box<dyn ErrorTrait + Send + Sync>) -> Self {
         ErrorKind::Custom(error)
     }
 }
 
-/// An error that may happen handling storage.
+/// The stage of encoding or decoding an entry in which an error happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Stage {
+    /// Encoding a key.
+    KeyEncode,
+    /// Encoding a value, including compression and encryption.
+    ValueEncode,
+    /// Decoding a stored key.
+    KeyDecode,
+    /// Decoding a stored value, including checksums, decryption and
+    /// decompression.
+    ValueDecode,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let stage = match self {
+            Stage::KeyEncode => "encoding key",
+            Stage::ValueEncode => "encoding value",
+            Stage::KeyDecode => "decoding key",
+            Stage::ValueDecode => "decoding value",
+        };
+        write!(fmt, "{}", stage)
+    }
+}
+
+/// An error that may happen handling storage, along with the context where it
+/// happened, when known.
 #[derive(Debug)]
 pub struct Error {
-    /// Kind of the error. Wrapped in a Box to reduce the stack size.
-    kind: Box<ErrorKind>,
+    /// The error and its context. Wrapped in a Box to reduce the stack size.
+    inner: Box<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    kind: ErrorKind,
+    stage: Option<Stage>,
+    operation: Option<&'static str>,
+    tree: Option<IVec>,
+    key: Option<IVec>,
 }
 
 impl Error {
-    /// Creates an error from its kind.
+    /// Creates an error from its kind, without context.
     pub fn new(kind: ErrorKind) -> Self {
-        Self { kind: Box::new(kind) }
+        let inner =
+            Inner { kind, stage: None, operation: None, tree: None, key: None };
+        Self { inner: Box::new(inner) }
+    }
+
+    /// Creates a conflict error, see [`ErrorKind::Conflict`].
+    pub fn conflict<E>(error: E) -> Self
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>>,
+    {
+        Self::new(ErrorKind::Conflict(error.into()))
+    }
+
+    /// Creates a constraint error, see [`ErrorKind::Constraint`].
+    pub fn constraint<E>(error: E) -> Self
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>>,
+    {
+        Self::new(ErrorKind::Constraint(error.into()))
     }
 
     /// Returns this error as a trait object.
     pub fn as_dyn(&self) -> &(dyn ErrorTrait + 'static + Send + Sync) {
-        self.kind.as_dyn()
+        self.inner.kind.as_dyn()
     }
 
     /// Returns the kind of the error.
     pub fn kind(&self) -> &ErrorKind {
-        &self.kind
+        &self.inner.kind
+    }
+
+    /// Returns a reference to the underlying error if it has type `T`. Works
+    /// with every kind, including custom, conflict and constraint errors.
+    pub fn downcast_ref<T>(&self) -> Option<&T>
+    where
+        T: ErrorTrait + 'static,
+    {
+        self.as_dyn().downcast_ref()
+    }
+
+    /// Takes the underlying error out if it has type `T`, otherwise returns
+    /// this error back. Works with custom, conflict and constraint errors.
+    pub fn downcast<T>(self) -> Result<T, Self>
+    where
+        T: ErrorTrait + 'static,
+    {
+        let Inner { kind, stage, operation, tree, key } = *self.inner;
+        let result = match kind {
+            ErrorKind::Custom(error) => {
+                error.downcast().map_err(ErrorKind::Custom)
+            },
+            ErrorKind::Conflict(error) => {
+                error.downcast().map_err(ErrorKind::Conflict)
+            },
+            ErrorKind::Constraint(error) => {
+                error.downcast().map_err(ErrorKind::Constraint)
+            },
+            kind => Err(kind),
+        };
+        result.map(|error| *error).map_err(|kind| {
+            let inner = Inner { kind, stage, operation, tree, key };
+            Self { inner: Box::new(inner) }
+        })
+    }
+
+    /// Returns the stage of encoding or decoding in which the error happened,
+    /// if any.
+    pub fn stage(&self) -> Option<Stage> {
+        self.inner.stage
+    }
+
+    /// Returns the name of the tree operation that failed, such as `"get"` or
+    /// `"insert"`, if known.
+    pub fn operation(&self) -> Option<&'static str> {
+        self.inner.operation
+    }
+
+    /// Returns the name of the tree where the error happened, if known.
+    pub fn tree(&self) -> Option<&[u8]> {
+        self.inner.tree.as_deref()
+    }
+
+    /// Returns the raw bytes of the encoded key of the entry involved in the
+    /// error, if known.
+    pub fn key(&self) -> Option<&[u8]> {
+        self.inner.key.as_deref()
+    }
+
+    /// Tests whether the error happened encoding a key or a value.
+    pub fn is_encode(&self) -> bool {
+        matches!(
+            self.stage(),
+            Some(Stage::KeyEncode) | Some(Stage::ValueEncode)
+        )
+    }
+
+    /// Tests whether the error happened decoding a key or a value.
+    pub fn is_decode(&self) -> bool {
+        matches!(
+            self.stage(),
+            Some(Stage::KeyDecode) | Some(Stage::ValueDecode)
+        )
+    }
+
+    /// Tests whether retrying the operation may succeed: conflicts, timeouts,
+    /// and I/O errors which are interrupted or timed out.
+    pub fn is_transient(&self) -> bool {
+        match self.kind() {
+            ErrorKind::Conflict(_) | ErrorKind::Timeout(_) => true,
+            ErrorKind::Sled(sled::Error::Io(error)) => matches!(
+                error.kind(),
+                io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+
+    /// Tests whether stored data was found corrupted, either by sled or by a
+    /// checksum mismatch.
+    pub fn is_corruption(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::Sled(sled::Error::Corruption { .. })
+                | ErrorKind::Corruption(_)
+        )
+    }
+
+    /// Tests whether this is a conflict error.
+    pub fn is_conflict(&self) -> bool {
+        matches!(self.kind(), ErrorKind::Conflict(_))
+    }
+
+    /// Tests whether this is a constraint error.
+    pub fn is_constraint(&self) -> bool {
+        matches!(self.kind(), ErrorKind::Constraint(_))
+    }
+
+    /// Tests whether a tree was not found, such as one dropped while in use.
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::Sled(sled::Error::CollectionNotFound(_))
+        )
+    }
+
+    /// Tests whether the operation is not supported, either by the tree's
+    /// configuration or by sled.
+    pub fn is_unsupported(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::Unsupported(_)
+                | ErrorKind::Sled(sled::Error::Unsupported(_))
+        )
+    }
+
+    /// Tests whether decoding exceeded the decode limit.
+    pub fn is_limit_exceeded(&self) -> bool {
+        matches!(self.kind(), ErrorKind::LimitExceeded(_))
+    }
+
+    /// Tests whether a string is not a valid encoded ID.
+    pub fn is_invalid_id(&self) -> bool {
+        matches!(self.kind(), ErrorKind::InvalidId(_))
+    }
+
+    /// Tests whether an ID generation did not complete within its timeout.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self.kind(), ErrorKind::Timeout(_))
+    }
+
+    /// Tests whether this is an I/O error of the storage.
+    pub fn is_io(&self) -> bool {
+        matches!(self.kind(), ErrorKind::Sled(sled::Error::Io(_)))
+    }
+
+    /// Records the stage in which the error happened, unless already known.
+    pub(crate) fn in_stage(mut self, stage: Stage) -> Self {
+        self.inner.stage.get_or_insert(stage);
+        self
+    }
+
+    /// Records the operation and the tree in which the error happened, unless
+    /// already known.
+    pub(crate) fn in_operation(
+        mut self,
+        operation: &'static str,
+        tree: &sled::Tree,
+    ) -> Self {
+        self.inner.operation.get_or_insert(operation);
+        self.inner.tree.get_or_insert_with(|| tree.name());
+        self
+    }
+
+    /// Records the tree and the encoded key of the entry involved in the
+    /// error, unless already known.
+    pub(crate) fn at(mut self, tree: &sled::Tree, key: &[u8]) -> Self {
+        if let ErrorKind::Corruption(error) = &mut self.inner.kind {
+            error.tree = tree.name();
+            error.key = IVec::from(key);
+        }
+        self.inner.tree.get_or_insert_with(|| tree.name());
+        self.inner.key.get_or_insert_with(|| IVec::from(key));
+        self
+    }
+
+    /// Records the encoded key of the entry involved in the error, unless
+    /// already known, or unless the key itself failed to be encoded.
+    pub(crate) fn at_key(mut self, key: &[u8]) -> Self {
+        if self.inner.stage != Some(Stage::KeyEncode) {
+            self.inner.key.get_or_insert_with(|| IVec::from(key));
+        }
+        self
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut separator = "";
+        if let Some(operation) = self.inner.operation {
+            write!(fmt, "{}", operation)?;
+            separator = " ";
+        }
+        if let Some(tree) = &self.inner.tree {
+            let tree = String::from_utf8_lossy(tree);
+            write!(fmt, "{}in tree {:?}", separator, tree)?;
+            separator = " ";
+        }
+        if let Some(key) = &self.inner.key {
+            write!(fmt, "{}at key {:?}", separator, key.as_ref())?;
+            separator = " ";
+        }
+        if let Some(stage) = self.inner.stage {
+            write!(fmt, "{}while {}", separator, stage)?;
+            separator = " ";
+        }
+        if !separator.is_empty() {
+            write!(fmt, ": ")?;
+        }
         write!(fmt, "{}", self.as_dyn())
     }
 }
@@ -97,8 +425,519 @@ impl From<sled::Error> for Error {
     }
 }
 
+impl From<AuthenticationError> for Error {
+    fn from(error: AuthenticationError) -> Self {
+        Self::new(ErrorKind::from(error))
+    }
+}
+
+impl From<Corruption> for Error {
+    fn from(error: Corruption) -> Self {
+        Self::new(ErrorKind::from(error))
+    }
+}
+
+impl From<LimitExceeded> for Error {
+    fn from(error: LimitExceeded) -> Self {
+        Self::new(ErrorKind::from(error))
+    }
+}
+
+impl From<InvalidId> for Error {
+    fn from(error: InvalidId) -> Self {
+        Self::new(ErrorKind::from(error))
+    }
+}
+
+impl From<Timeout> for Error {
+    fn from(error: Timeout) -> Self {
+        Self::new(ErrorKind::from(error))
+    }
+}
+
+impl From<Unsupported> for Error {
+    fn from(error: Unsupported) -> Self {
+        Self::new(ErrorKind::from(error))
+    }
+}
+
+impl From<TransactionError<Error>> for Error {
+    fn from(error: TransactionError<Error>) -> Self {
+        match error {
+            TransactionError::Abort(error) => error,
+            TransactionError::Storage(error) => Self::from(error),
+        }
+    }
+}
+
 impl From<Box<dyn ErrorTrait + Send + Sync>> for Error {
     fn from(error: Box<dyn ErrorTrait + Send + Sync>) -> Self {
         Self::new(ErrorKind::from(error))
     }
 }
+
+impl<E> From<TxError<E>> for Error
+where
+    E: Into<Error>,
+{
+    fn from(error: TxError<E>) -> Self {
+        match error {
+            TxError::Abort(error) => error.into(),
+            TxError::Storage(error) => error,
+        }
+    }
+}
+
+/// An error of an operation running a user closure, such as
+/// [`Tree::update`](crate::tree::Tree::update), which either aborted with the
+/// user error type `E`, kept as it is, or failed handling storage.
+#[derive(Debug)]
+pub enum TxError<E> {
+    /// The user closure aborted with the given error.
+    Abort(E),
+    /// An error happened handling storage.
+    Storage(Error),
+}
+
+impl<E> TxError<E> {
+    /// Returns the user error, if the closure aborted.
+    pub fn abort(&self) -> Option<&E> {
+        match self {
+            TxError::Abort(error) => Some(error),
+            TxError::Storage(_) => None,
+        }
+    }
+
+    /// Takes the user error out, if the closure aborted, otherwise returns the
+    /// storage error.
+    pub fn into_abort(self) -> Result<E, Error> {
+        match self {
+            TxError::Abort(error) => Ok(error),
+            TxError::Storage(error) => Err(error),
+        }
+    }
+
+    /// Returns the storage error, if any.
+    pub fn storage(&self) -> Option<&Error> {
+        match self {
+            TxError::Abort(_) => None,
+            TxError::Storage(error) => Some(error),
+        }
+    }
+
+    /// Transforms the user error with the given function.
+    pub fn map_abort<F, E0>(self, map: F) -> TxError<E0>
+    where
+        F: FnOnce(E) -> E0,
+    {
+        match self {
+            TxError::Abort(error) => TxError::Abort(map(error)),
+            TxError::Storage(error) => TxError::Storage(error),
+        }
+    }
+
+    /// Transforms the storage error with the given function.
+    pub(crate) fn map_storage<F>(self, map: F) -> Self
+    where
+        F: FnOnce(Error) -> Error,
+    {
+        match self {
+            TxError::Abort(error) => TxError::Abort(error),
+            TxError::Storage(error) => TxError::Storage(map(error)),
+        }
+    }
+}
+
+impl<E> fmt::Display for TxError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxError::Abort(error) => write!(fmt, "{}", error),
+            TxError::Storage(error) => write!(fmt, "{}", error),
+        }
+    }
+}
+
+impl<E> ErrorTrait for TxError<E>
+where
+    E: ErrorTrait + 'static,
+{
+    fn source(&self) -> Option<&(dyn ErrorTrait + 'static)> {
+        match self {
+            TxError::Abort(error) => Some(error),
+            TxError::Storage(error) => Some(error),
+        }
+    }
+}
+
+impl<E> From<Error> for TxError<E> {
+    fn from(error: Error) -> Self {
+        TxError::Storage(error)
+    }
+}
+
+impl<E> From<TransactionError<TxError<E>>> for TxError<E> {
+    fn from(error: TransactionError<TxError<E>>) -> Self {
+        match error {
+            TransactionError::Abort(error) => error,
+            TransactionError::Storage(error) => {
+                TxError::Storage(Error::from(error))
+            },
+        }
+    }
+}
+
+/// Error returned when an operation requires a feature the tree was not opened
+/// with.
+#[derive(Debug, Clone)]
+pub struct Unsupported {
+    operation: &'static str,
+    requirement: &'static str,
+}
+
+impl Unsupported {
+    /// Creates an error for the given operation, which requires the given
+    /// feature.
+    pub fn new(operation: &'static str, requirement: &'static str) -> Self {
+        Self { operation, requirement }
+    }
+
+    /// Returns the name of the unsupported operation.
+    pub fn operation(&self) -> &'static str {
+        self.operation
+    }
+
+    /// Returns the feature the operation requires.
+    pub fn requirement(&self) -> &'static str {
+        self.requirement
+    }
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} requires {}", self.operation, self.requirement)
+    }
+}
+
+impl ErrorTrait for Unsupported {}
+
+/// Error returned when an encrypted value cannot be authenticated, either
+/// because its key is not known, or because the value or the key are wrong.
+#[derive(Debug, Clone)]
+pub struct AuthenticationError {
+    key_id: u32,
+    unknown_key: bool,
+}
+
+impl AuthenticationError {
+    /// Creates an error for a value encrypted with the given key, which may
+    /// not be known.
+    pub fn new(key_id: u32, unknown_key: bool) -> Self {
+        Self { key_id, unknown_key }
+    }
+
+    /// Returns the identifier of the key the value was encrypted with.
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// Returns whether the key the value was encrypted with is not known.
+    pub fn is_unknown_key(&self) -> bool {
+        self.unknown_key
+    }
+}
+
+impl fmt::Display for AuthenticationError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.unknown_key {
+            write!(fmt, "value encrypted with unknown key {}", self.key_id)
+        } else {
+            write!(fmt, "authentication with key {} failed", self.key_id)
+        }
+    }
+}
+
+impl ErrorTrait for AuthenticationError {}
+
+/// Error returned when data to decode, or the output of decompressing it, has
+/// more bytes than the decode limit. See
+/// [`set_decode_limit`](crate::set_decode_limit).
+#[derive(Debug, Clone)]
+pub struct LimitExceeded {
+    limit: u64,
+}
+
+impl LimitExceeded {
+    /// Creates an error for the given limit, in bytes.
+    pub fn new(limit: u64) -> Self {
+        Self { limit }
+    }
+
+    /// Returns the limit that was exceeded, in bytes.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "data to decode exceeds the limit of {} bytes", self.limit)
+    }
+}
+
+impl ErrorTrait for LimitExceeded {}
+
+/// Error returned when an operation did not complete within its timeout, such
+/// as the one set by [`IdBuilder::timeout`](crate::tree::IdBuilder::timeout).
+#[derive(Debug, Clone)]
+pub struct Timeout {
+    duration: Duration,
+}
+
+impl Timeout {
+    /// Creates an error for the given timeout.
+    pub fn new(duration: Duration) -> Self {
+        Self { duration }
+    }
+
+    /// Returns the timeout that elapsed.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "operation timed out after {:?}", self.duration)
+    }
+}
+
+impl ErrorTrait for Timeout {}
+
+/// Error returned when a string is not an ID encoded by an
+/// [`IdEncoder`](crate::tree::IdEncoder).
+#[derive(Debug, Clone)]
+pub struct InvalidId {
+    input: String,
+}
+
+impl InvalidId {
+    /// Creates an error for the given input string.
+    pub fn new(input: &str) -> Self {
+        Self { input: input.to_owned() }
+    }
+
+    /// Returns the input string.
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+}
+
+impl fmt::Display for InvalidId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{:?} is not a valid encoded ID", self.input)
+    }
+}
+
+impl ErrorTrait for InvalidId {}
+
+/// Error returned when a stored value does not match its checksum, and so it
+/// was not decoded.
+#[derive(Debug, Clone)]
+pub struct Corruption {
+    tree: IVec,
+    key: IVec,
+}
+
+impl Corruption {
+    /// Creates an error for a corrupted value, found in the tree with the given
+    /// name, under the given encoded key.
+    pub fn new(tree: &[u8], key: &[u8]) -> Self {
+        Self { tree: IVec::from(tree), key: IVec::from(key) }
+    }
+
+    /// Returns the name of the tree where the corrupted value was found, or
+    /// an empty name if the value was not read from a tree.
+    pub fn tree(&self) -> &[u8] {
+        &self.tree
+    }
+
+    /// Returns the raw bytes of the key of the corrupted value, or empty bytes
+    /// if the value was not read from a tree.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "value does not match its checksum")
+    }
+}
+
+impl ErrorTrait for Corruption {}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Error, LimitExceeded, Stage, Timeout, TxError, Unsupported,
+    };
+    use crate::tree::{OpenOptions, Tree};
+    use serde::{ser, Serialize, Serializer};
+    use std::{fmt, io, time::Duration};
+
+    /// A type which always fails to serialize.
+    #[derive(Debug)]
+    struct Unencodable;
+
+    impl Serialize for Unencodable {
+        fn serialize<S>(&self, _: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            Err(ser::Error::custom("unencodable"))
+        }
+    }
+
+    /// A user error, kept as it is by operations running user closures.
+    #[derive(Debug, PartialEq, Eq)]
+    struct Rejected(u8);
+
+    impl fmt::Display for Rejected {
+        fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            write!(fmt, "rejected {}", self.0)
+        }
+    }
+
+    impl std::error::Error for Rejected {}
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn context_of_failed_operations() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Tree::<String, u64>::open(&db, "tree").await.unwrap();
+        let storage = db.open_tree("tree").unwrap();
+        let encoded_key = crate::encode("bad").unwrap();
+        storage.insert(&encoded_key, &[0xff]).unwrap();
+
+        let error = tree.get("bad").await.unwrap_err();
+        assert_eq!(error.operation(), Some("get"));
+        assert_eq!(error.tree(), Some(&b"tree"[..]));
+        assert_eq!(error.key(), Some(&encoded_key[..]));
+        assert_eq!(error.stage(), Some(Stage::ValueDecode));
+        assert!(error.is_decode() && !error.is_encode());
+        let message = error.to_string();
+        let context = "get in tree \"tree\" at key";
+        assert!(message.starts_with(context), "{}", message);
+        assert!(message.contains("while decoding value"), "{}", message);
+
+        let error = tree.remove("bad").await.unwrap_err();
+        assert_eq!(error.operation(), Some("remove"));
+        assert_eq!(error.stage(), Some(Stage::ValueDecode));
+
+        let tree = Tree::<Unencodable, u8>::open(&db, "other").await.unwrap();
+        let error = tree.contains_key(&Unencodable).await.unwrap_err();
+        assert_eq!(error.operation(), Some("contains_key"));
+        assert_eq!(error.tree(), Some(&b"other"[..]));
+        assert_eq!(error.key(), None);
+        assert_eq!(error.stage(), Some(Stage::KeyEncode));
+        assert!(error.is_encode() && !error.is_decode());
+
+        let options = OpenOptions::new().envelope(true);
+        let tree = Tree::<u8, Unencodable>::open_with(&db, "other", &options)
+            .await
+            .unwrap();
+        let error = tree.insert_if_version(&1, &Unencodable, None).await;
+        let error = error.unwrap_err();
+        assert_eq!(error.operation(), Some("insert_if_version"));
+        assert_eq!(error.stage(), Some(Stage::ValueEncode));
+        assert_eq!(error.key(), Some(&crate::encode(1u8).unwrap()[..]));
+    }
+
+    #[test]
+    fn classify_errors() {
+        let interrupted = io::Error::from(io::ErrorKind::Interrupted);
+        let error = Error::from(sled::Error::Io(interrupted));
+        assert!(error.is_io() && error.is_transient());
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        let error = Error::from(sled::Error::Io(denied));
+        assert!(error.is_io() && !error.is_transient());
+
+        let error = Error::from(sled::Error::Corruption { at: None, bt: () });
+        assert!(error.is_corruption() && !error.is_transient());
+        let error = Error::from(sled::Error::CollectionNotFound("t".into()));
+        assert!(error.is_not_found());
+        let error = Error::from(sled::Error::Unsupported("no".into()));
+        assert!(error.is_unsupported());
+        let error = Error::from(Unsupported::new("get_meta", "envelopes"));
+        assert!(error.is_unsupported() && !error.is_io());
+
+        let error = Error::conflict("taken");
+        assert!(error.is_conflict() && error.is_transient());
+        assert!(!error.is_constraint());
+        let error = Error::constraint("too long");
+        assert!(error.is_constraint() && !error.is_transient());
+        assert!(!error.is_conflict());
+        let error = Error::from(Timeout::new(Duration::from_secs(1)));
+        assert!(error.is_timeout() && error.is_transient());
+        let error = Error::from(LimitExceeded::new(8));
+        assert!(error.is_limit_exceeded() && !error.is_corruption());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn classify_errors_of_operations() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Tree::<u8, u8>::open(&db, "tree").await.unwrap();
+        let error = tree.get_meta(&0).await.unwrap_err();
+        assert!(error.is_unsupported() && !error.is_transient());
+
+        let options = OpenOptions::new().envelope(true);
+        let tree = Tree::<u8, u8>::open_with(&db, "other", &options)
+            .await
+            .unwrap();
+        tree.insert(&0, &0).await.unwrap();
+        let mismatch = tree.insert_if_version(&0, &1, None).await.unwrap();
+        let error = Error::from(mismatch.unwrap_err());
+        assert!(error.is_conflict() && error.is_transient());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn update_gives_back_user_errors() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Tree::<u8, u8>::open(&db, "tree").await.unwrap();
+        let increment = |old: Option<u8>| Ok(Some(old.unwrap_or(0) + 1));
+        let new = tree.update::<_, Rejected>(&0, increment).await;
+        assert_eq!(new.unwrap(), Some(1));
+
+        let error = tree
+            .update(&0, |old| Err(Rejected(old.unwrap())))
+            .await
+            .unwrap_err();
+        assert_eq!(error.abort(), Some(&Rejected(1)));
+        assert!(error.storage().is_none());
+        assert_eq!(error.to_string(), "rejected 1");
+        let error = error.map_abort(|Rejected(code)| code + 1);
+        assert_eq!(error.into_abort().unwrap(), 2);
+        assert_eq!(tree.get(&0).await.unwrap(), Some(1));
+
+        let removed = tree.update(&0, |_| Ok::<_, Rejected>(None));
+        assert_eq!(removed.await.unwrap(), None);
+        assert!(!tree.contains_key(&0).await.unwrap());
+
+        let storage = db.open_tree("tree").unwrap();
+        storage.insert(crate::encode(1u8).unwrap(), &[]).unwrap();
+        let error = tree.update(&1, |_| Err(Rejected(0))).await.unwrap_err();
+        assert!(error.abort().is_none());
+        let error = error.into_abort().unwrap_err();
+        assert_eq!(error.operation(), Some("update"));
+        assert!(error.is_decode());
+
+        let error = tree.update(&0, |_| Err(Rejected(3))).await.unwrap_err();
+        let error = Error::from(error.map_abort(Error::constraint));
+        assert!(error.is_constraint());
+        assert_eq!(error.downcast::<Rejected>().unwrap(), Rejected(3));
+        let error: TxError<Rejected> = TxError::from(Error::conflict("busy"));
+        assert!(error.storage().unwrap().is_conflict());
+    }
+}
@@ -2,11 +2,18 @@
 
 pub mod error;
 pub mod buffer;
+pub mod codec;
 pub mod tree;
 
-use crate::error::Error;
+use crate::{
+    codec::Compression,
+    error::{Error, LimitExceeded},
+};
 use bincode::Options;
-use std::path::Path;
+use std::{
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
 use tokio::task;
 
 /// Opens a database in the given path.
@@ -17,6 +24,28 @@ where
     task::block_in_place(|| Ok(sled::open(path)?))
 }
 
+/// Maximum size, in bytes, of data to decode and of decompressed data, used
+/// when no other limit is given. `u64::MAX` stands for no limit.
+static DECODE_LIMIT: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Sets the maximum size, in bytes, of encoded data to decode, or removes the
+/// limit if `None`. This applies to every decoding, and to the output of
+/// decompression, except for trees opened with their own limit. By default,
+/// there is no limit, so a corrupted or malicious compressed value may
+/// decompress into a lot of memory.
+pub fn set_decode_limit(limit: Option<u64>) {
+    DECODE_LIMIT.store(limit.unwrap_or(u64::MAX), Ordering::Relaxed);
+}
+
+/// Returns the maximum size, in bytes, of encoded data to decode, set by
+/// [`set_decode_limit`].
+pub fn decode_limit() -> Option<u64> {
+    match DECODE_LIMIT.load(Ordering::Relaxed) {
+        u64::MAX => None,
+        limit => Some(limit),
+    }
+}
+
 /// Default configs for bincode.
 fn config() -> impl Options {
     bincode::DefaultOptions::new().with_no_limit().with_big_endian()
@@ -41,11 +70,46 @@ where
     Ok(buffer)
 }
 
-/// Decodes a value from binary.
+/// Decodes a value from binary, within the limit set by [`set_decode_limit`].
 pub fn decode<'de, T>(bytes: &'de [u8]) -> Result<T, Error>
 where
     T: serde::Deserialize<'de>,
 {
-    let data = config().deserialize(bytes)?;
-    Ok(data)
+    decode_limited(bytes, DECODE_LIMIT.load(Ordering::Relaxed))
+}
+
+/// Decodes a value from binary, failing if the input has more than `limit`
+/// bytes. bincode ignores its own limit when decoding from a slice, so the
+/// input is checked instead: as trailing bytes are rejected and bincode only
+/// allocates in proportion to what it reads, this bounds decoding too.
+pub(crate) fn decode_limited<'de, T>(
+    bytes: &'de [u8],
+    limit: u64,
+) -> Result<T, Error>
+where
+    T: serde::Deserialize<'de>,
+{
+    if bytes.len() as u64 > limit {
+        return Err(LimitExceeded::new(limit).into());
+    }
+    Ok(config().deserialize(bytes)?)
+}
+
+/// Decodes a value from binary, after decompressing the bytes if compression
+/// settings are given, within the limit set by [`set_decode_limit`]. This is
+/// the inverse of [`Buffer::encode_with`](buffer::Buffer::encode_with).
+pub fn decode_with<T>(
+    bytes: &[u8],
+    compression: Option<&Compression>,
+) -> Result<T, Error>
+where
+    for<'de> T: serde::Deserialize<'de>,
+{
+    let limit = DECODE_LIMIT.load(Ordering::Relaxed);
+    match compression {
+        Some(_) => {
+            decode_limited(&Compression::decompress(bytes, limit)?, limit)
+        },
+        None => decode_limited(bytes, limit),
+    }
 }
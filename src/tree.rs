@@ -1,55 +1,957 @@
 //! Exports a persistent, serializing/deserializing ordered tree.
 
+mod expiry;
+mod envelope;
+mod versioned;
+mod verify;
+mod iter;
+mod borrowed;
+mod typed_id;
+mod sequence;
+mod id_source;
+mod id_encoder;
+mod transaction;
+
+#[cfg(feature = "snowflake")]
+pub use self::id_source::Snowflake;
+#[cfg(feature = "ulid")]
+pub use self::id_source::Ulids;
+#[cfg(feature = "uuid")]
+pub use self::id_source::{UuidV4, UuidV7};
+pub use self::{
+    borrowed::{Borrowable, Ref},
+    envelope::{Metadata, VersionMismatch},
+    id_encoder::IdEncoder,
+    id_source::{DbIds, IdSource, SequenceIds},
+    iter::Iter,
+    transaction::{TreeTx, TxTrees},
+    typed_id::TypedId,
+    verify::{InvalidEntry, VerifyReport},
+    versioned::{Current, Retention, Versioned},
+};
+
+use self::{
+    envelope::LatestTx,
+    expiry::{Expiry, ExpiryTx},
+    sequence::Sequence,
+};
+#[cfg(feature = "chacha20poly1305")]
+use crate::codec::Encryption;
 use crate::{
     buffer::{self, Buffer},
-    decode,
-    error::Error,
+    codec::{Checksum, Codec, Compression, Context},
+    decode_limited,
+    error::{Error, Stage, Timeout, TxError, Unsupported},
 };
 use futures::future::{FutureExt, Map};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, TransactionError, Transactional,
+        TransactionalTree,
+    },
+    IVec,
+};
 use std::{
+    borrow::{Borrow, Cow},
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
     fmt,
     future::{ready, Future, Ready},
     marker::PhantomData,
+    ops::{Bound, RangeBounds},
+    time::Duration,
+};
+use tokio::{
+    task::{self, JoinHandle},
+    time,
 };
-use tokio::task;
 
 /// An ID generated by the tree.
 pub type Id = u64;
 
-/// A persistent key-value structure.
-pub struct Tree<K, V>
+/// Maximum number of removals applied in a single atomic batch by bulk removal
+/// operations.
+const REMOVE_BATCH_SIZE: usize = 1024;
+
+/// Makes the name of a companion tree used internally by the tree with the
+/// given name.
+fn companion_name(name: &[u8], suffix: &str) -> Vec<u8> {
+    let mut companion = Vec::with_capacity(name.len() + suffix.len() + 1);
+    companion.extend_from_slice(name);
+    companion.push(0);
+    companion.extend_from_slice(suffix.as_bytes());
+    companion
+}
+
+/// Options for opening a tree. See [`Tree::open_with`].
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions<A = buffer::DefaultPool> {
+    expiry: bool,
+    envelope: bool,
+    sequence: Option<u64>,
+    id_encoder: IdEncoder,
+    codec: Codec,
+    call: CallOptions<A>,
+}
+
+impl OpenOptions {
+    /// Creates the default options: every feature is disabled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<A> OpenOptions<A> {
+    /// Sets the options every operation of the tree uses by default, see
+    /// [`CallOptions`]. They can be overridden later with
+    /// [`Tree::with_options`].
+    pub fn call_options<A0>(self, call: CallOptions<A0>) -> OpenOptions<A0> {
+        OpenOptions {
+            expiry: self.expiry,
+            envelope: self.envelope,
+            sequence: self.sequence,
+            id_encoder: self.id_encoder,
+            codec: self.codec,
+            call,
+        }
+    }
+
+    /// Enables or disables time-to-live expiry of entries. See
+    /// [`Tree::insert_with_ttl`]. Deadlines are kept in companion trees, and
+    /// every read also checks the deadline of the entry, so this is disabled
+    /// by default.
+    pub fn expiry(mut self, enabled: bool) -> Self {
+        self.expiry = enabled;
+        self
+    }
+
+    /// Enables or disables value envelopes. An envelope stores the value
+    /// along with its [`Metadata`], which is required by [`Tree::get_meta`]
+    /// and [`Tree::insert_if_version`]. The latest version of each key is
+    /// kept in a companion tree, so versions are not reused after an entry is
+    /// removed. Envelopes change how values are stored, so a tree must always
+    /// be opened with the same setting.
+    pub fn envelope(mut self, enabled: bool) -> Self {
+        self.envelope = enabled;
+        self
+    }
+
+    /// Enables the sequence of the tree, see [`Tree::next_sequence`],
+    /// reserving `block_size` numbers at a time. Every reservation is flushed
+    /// to disk, so a larger block improves throughput, but the numbers of a
+    /// block not yet handed out are skipped after the tree is reopened. Clones
+    /// of a handle share a block, while handles opened separately reserve
+    /// their own, so their numbers interleave. A block size of 1 produces
+    /// numbers without gaps.
+    pub fn sequence(mut self, block_size: u64) -> Self {
+        self.sequence = Some(block_size);
+        self
+    }
+
+    /// Sets the encoder of IDs as strings, see [`Tree::encode_id`]. By
+    /// default, the [`IdEncoder::default`] encoder is used.
+    pub fn id_encoder(mut self, encoder: IdEncoder) -> Self {
+        self.id_encoder = encoder;
+        self
+    }
+
+    /// Enables compression of values with the given settings. See
+    /// [`Compression`] for how changing the settings affects existing data.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.codec = self.codec.compression(compression);
+        self
+    }
+
+    /// Sets the maximum size, in bytes, of encoded keys and values to decode,
+    /// and of their decompressed values, overriding the limit set by
+    /// [`set_decode_limit`](crate::set_decode_limit). Exceeding it produces
+    /// [`ErrorKind::LimitExceeded`](crate::error::ErrorKind) instead of
+    /// allocating the memory.
+    pub fn decode_limit(mut self, limit: u64) -> Self {
+        self.codec = self.codec.decode_limit(limit);
+        self
+    }
+
+    /// Enables checksums of values with the given algorithm, so corrupted
+    /// values produce [`ErrorKind::Corruption`](crate::error::ErrorKind)
+    /// instead of being decoded. Values written without checksums cannot be
+    /// read with checksums and vice-versa.
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.codec = self.codec.checksum(checksum);
+        self
+    }
+
+    /// Enables encryption of values with the given settings. Values written
+    /// without encryption cannot be read with encryption and vice-versa. See
+    /// [`Tree::reencrypt`] for key rotation.
+    #[cfg(feature = "chacha20poly1305")]
+    pub fn encryption(mut self, encryption: Encryption) -> Self {
+        self.codec = self.codec.encryption(encryption);
+        self
+    }
+}
+
+/// When the writes of an operation are persisted to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Writes are persisted in the background, according to the flush
+    /// settings of the database, so a crash may lose the latest ones.
+    #[default]
+    Buffered,
+    /// The tree is flushed to disk before a write operation returns.
+    Flushed,
+}
+
+/// Options of the operations of a tree: the allocation strategy of the buffers
+/// used to serialize keys and values, the [`Durability`] of writes and a
+/// timeout of ID generations. A tree is opened with default options, see
+/// [`OpenOptions::call_options`], and [`Tree::with_options`] overrides them.
+#[derive(Debug, Clone, Default)]
+pub struct CallOptions<A = buffer::DefaultPool> {
+    allocation: A,
+    durability: Durability,
+    timeout: Option<Duration>,
+}
+
+impl CallOptions {
+    /// Creates the default options: a [`buffer::DefaultPool`] allocation,
+    /// buffered writes and no timeout. To override only some of the options
+    /// of a tree, e.g. for a single call, start from `tree.options().clone()`
+    /// instead, which keeps the allocation of the tree.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<A> CallOptions<A> {
+    /// Changes the allocation strategy of the buffers.
+    pub fn allocation<A0>(self, allocation: A0) -> CallOptions<A0>
+    where
+        A0: buffer::Allocation + Clone,
+    {
+        CallOptions {
+            allocation,
+            durability: self.durability,
+            timeout: self.timeout,
+        }
+    }
+
+    /// Sets the durability of writes.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets a timeout for ID generations, after which they fail with
+    /// [`ErrorKind::Timeout`](crate::error::ErrorKind), see
+    /// [`IdBuilder::timeout`]. The timeout bounds the time a generation waits,
+    /// e.g. for the "id maker" or between its attempts, but storage accesses
+    /// are blocking and are not interrupted, so a generation may take longer
+    /// than the timeout. Other operations only access storage, so they are
+    /// not bounded. Flushing for [`Durability::Flushed`] is not bounded
+    /// either.
+    pub fn timeout(mut self, duration: Duration) -> Self {
+        self.timeout = Some(duration);
+        self
+    }
+
+    /// Removes the timeout.
+    pub fn no_timeout(mut self) -> Self {
+        self.timeout = None;
+        self
+    }
+}
+
+/// Result of a closure running inside of a transaction.
+type TxResult<T, E = Error> = Result<T, ConflictableTransactionError<E>>;
+
+/// How to replace an entry inside of a transaction.
+enum Replace<'val> {
+    /// Inserts the given encoded value.
+    Put(Cow<'val, [u8]>),
+    /// Removes the entry.
+    Remove,
+    /// Leaves the entry as it is.
+    Keep,
+}
+
+/// When an entry is replaced inside of a transaction, and the latest version
+/// of its key, or `0` without envelopes.
+#[derive(Debug, Clone, Copy)]
+struct Stamp {
+    now: u64,
+    latest: u64,
+}
+
+impl<'val> Replace<'val> {
+    /// Makes this replacement own its encoded value.
+    fn into_owned(self) -> Replace<'static> {
+        match self {
+            Replace::Put(encoded_value) => {
+                Replace::Put(Cow::Owned(encoded_value.into_owned()))
+            },
+            Replace::Remove => Replace::Remove,
+            Replace::Keep => Replace::Keep,
+        }
+    }
+}
+
+/// Re-encrypts every value of `storage` which is not encrypted with the current
+/// key, returning how many values were re-encrypted. `header_size` is the size
+/// of the header stored before each value. Values changed concurrently are left
+/// as they are, since they are already written with the current key. Blocking.
+#[cfg(feature = "chacha20poly1305")]
+fn reencrypt_storage(
+    storage: &sled::Tree,
+    codec: &Codec,
+    header_size: usize,
+) -> Result<usize, Error> {
+    let mut reencrypted = 0;
+    for entry in storage.iter() {
+        let (key, stored) = entry?;
+        let context = Context::new(storage, &key);
+        let new = codec
+            .reencrypt(context, &stored, header_size)
+            .map_err(|error| error.at(storage, &key))?;
+        if let Some(new) = new {
+            if storage.compare_and_swap(&key, Some(&stored), Some(new))?.is_ok()
+            {
+                reencrypted += 1;
+            }
+        }
+    }
+    Ok(reencrypted)
+}
+
+/// Encodes a key using the given buffer.
+fn encode_key<'buf, T>(
+    buffer: &'buf mut Buffer,
+    key: &T,
+) -> Result<&'buf [u8], Error>
 where
-    for<'de> K: serde::Serialize + serde::Deserialize<'de>,
-    for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+    T: serde::Serialize + ?Sized,
 {
-    storage: sled::Tree,
-    _marker: PhantomData<(K, V)>,
+    buffer.encode(key).map_err(|error| error.in_stage(Stage::KeyEncode))
+}
+
+/// Encodes a value using the given buffer, compressing it with the codec.
+fn encode_value<'buf, T>(
+    buffer: &'buf mut Buffer,
+    val: &T,
+    codec: &Codec,
+) -> Result<&'buf [u8], Error>
+where
+    T: serde::Serialize + ?Sized,
+{
+    buffer
+        .encode_with(val, codec.compression_settings())
+        .map_err(|error| error.in_stage(Stage::ValueEncode))
+}
+
+/// Encodes a range bound over keys into a range bound over bytes.
+fn encode_bound<'buf, K>(
+    bound: Bound<&K>,
+    buffer: &'buf mut Buffer,
+) -> Result<Bound<&'buf [u8]>, Error>
+where
+    K: serde::Serialize,
+{
+    Ok(match bound {
+        Bound::Included(key) => Bound::Included(encode_key(buffer, key)?),
+        Bound::Excluded(key) => Bound::Excluded(encode_key(buffer, key)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
 }
 
-impl<K, V> Tree<K, V>
+/// Runs the given future, failing with a timeout error if it does not
+/// complete within the given duration, if any.
+async fn with_timeout<F, T, E>(
+    timeout: Option<Duration>,
+    future: F,
+) -> Result<T, E>
 where
-    for<'de> K: serde::Serialize + serde::Deserialize<'de>,
-    for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+    F: Future<Output = Result<T, E>>,
+    E: From<Error>,
 {
+    match timeout {
+        Some(duration) => time::timeout(duration, future).await.unwrap_or_else(
+            |_| Err(Error::from(Timeout::new(duration)).into()),
+        ),
+        None => future.await,
+    }
+}
+
+/// A persistent key-value structure.
+///
+/// Lookups such as [`Tree::get`], [`Tree::contains_key`] and [`Tree::remove`]
+/// accept any borrowed form `Q` of the key type, like `HashMap` does, so a
+/// `Tree<String, V>` can be queried with a `&str` without allocating. The
+/// borrowed form must serialize to the same bytes as the key, which holds for
+/// the forms serde treats alike, such as `str` and `String`, `[T]` and
+/// `Vec<T>`, or `T` and `Box<T>`.
+///
+/// The tree places no bounds on `K` and `V` by itself: each operation requires
+/// only the traits it uses to encode or decode them, and the tree is `Send`,
+/// `Sync` and `Clone` whenever `A` is.
+///
+/// Every operation uses the [`CallOptions`] of the tree, set when it is opened
+/// by [`OpenOptions::call_options`]: `A` is the allocation strategy of the
+/// buffers serializing keys and values, which each operation clones, so a
+/// [`buffer::SharedPool`] is shared by all operations while the default
+/// [`buffer::DefaultPool`] is a thread-local pool. [`Tree::with_options`]
+/// overrides the options, e.g. for a single call, returning a [`TreeRef`].
+pub struct Tree<K, V, A = buffer::DefaultPool, S = Shared> {
+    shared: S,
+    options: CallOptions<A>,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+/// A handle to a tree which borrows everything but its [`CallOptions`] from
+/// another handle, as returned by [`Tree::with_options`]. It has every
+/// operation of [`Tree`].
+pub type TreeRef<'tree, K, V, A = buffer::DefaultPool> =
+    Tree<K, V, A, &'tree Shared>;
+
+/// The storage of a tree and the settings it was opened with, which every
+/// handle to the tree shares. Handles own it, except for a [`TreeRef`], which
+/// borrows it.
+#[derive(Debug, Clone)]
+pub struct Shared {
+    storage: sled::Tree,
+    expiry: Option<Expiry>,
+    envelope: bool,
+    latest: Option<sled::Tree>,
+    sequence: Option<Sequence>,
+    id_encoder: IdEncoder,
+    codec: Codec,
+}
+
+impl Shared {
+    /// Returns the companion trees which transactions writing entries must
+    /// include: the latest versions, if envelopes are enabled, followed by
+    /// the expiry trees, if expiry is enabled.
+    fn companions(&self) -> Vec<&sled::Tree> {
+        let mut trees = Vec::new();
+        trees.extend(&self.latest);
+        if let Some(expiry) = &self.expiry {
+            trees.extend_from_slice(&expiry.trees());
+        }
+        trees
+    }
+
+    /// Makes the views of the transactional versions of the trees returned by
+    /// [`Shared::companions`].
+    fn companion_views<'tx>(
+        &self,
+        trees: &'tx [TransactionalTree],
+    ) -> (Option<LatestTx<'tx>>, Option<ExpiryTx<'tx>>) {
+        let (latest, expiry) = match &self.latest {
+            Some(_) => (Some(LatestTx::new(&trees[0])), &trees[1..]),
+            None => (None, trees),
+        };
+        (latest, self.expiry.as_ref().map(|_| ExpiryTx::new(expiry)))
+    }
+}
+
+impl<K, V> Tree<K, V> {
     /// Opens this tree from a database.
     pub async fn open<T>(db: &sled::Db, name: T) -> Result<Self, Error>
     where
         T: AsRef<[u8]>,
     {
-        let storage = task::block_in_place(|| db.open_tree(name))?;
-        Ok(Self { storage, _marker: PhantomData })
+        Self::open_with(db, name, &OpenOptions::default()).await
+    }
+}
+
+impl<K, V, A> Tree<K, V, A> {
+    /// Opens this tree from a database, with the given options.
+    pub async fn open_with<T>(
+        db: &sled::Db,
+        name: T,
+        options: &OpenOptions<A>,
+    ) -> Result<Self, Error>
+    where
+        T: AsRef<[u8]>,
+        A: Clone,
+    {
+        let name = name.as_ref();
+        task::block_in_place(|| {
+            let storage = db.open_tree(name)?;
+            let expiry = if options.expiry {
+                Some(Expiry::open(db, name)?)
+            } else {
+                None
+            };
+            let latest = if options.envelope {
+                Some(envelope::open_latest(db, name)?)
+            } else {
+                None
+            };
+            let sequence = match options.sequence {
+                Some(block_size) => Some(Sequence::open(db, name, block_size)?),
+                None => None,
+            };
+            let shared = Shared {
+                storage,
+                expiry,
+                envelope: options.envelope,
+                latest,
+                sequence,
+                id_encoder: options.id_encoder.clone(),
+                codec: options.codec.clone(),
+            };
+            Ok(Self {
+                shared,
+                options: options.call.clone(),
+                _marker: PhantomData,
+            })
+        })
+    }
+}
+
+impl<K, V, A, S> Tree<K, V, A, S>
+where
+    S: Borrow<Shared>,
+{
+    /// Returns the parts of this tree shared by its handles.
+    fn shared(&self) -> &Shared {
+        self.shared.borrow()
+    }
+
+    /// Returns an owned handle to this tree, e.g. for a stream which outlives
+    /// a [`TreeRef`].
+    fn owned(&self) -> Tree<K, V, A>
+    where
+        A: Clone,
+    {
+        Tree {
+            shared: self.shared().clone(),
+            options: self.options.clone(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the options every operation of this tree uses.
+    pub fn options(&self) -> &CallOptions<A> {
+        &self.options
     }
 
-    async fn get_raw(
+    /// Returns a handle to this tree whose operations use the given options
+    /// instead, e.g. for a single call, as in
+    /// `tree.with_options(options).insert(&key, &val)`. The handle borrows
+    /// everything else from this tree, so it is cheap to make.
+    pub fn with_options<A0>(
         &self,
-        key: &K,
+        options: CallOptions<A0>,
+    ) -> TreeRef<'_, K, V, A0> {
+        Tree { shared: self.shared(), options, _marker: PhantomData }
+    }
+
+    /// Returns the allocation strategy for the buffers of an operation.
+    fn allocation(&self) -> A
+    where
+        A: Clone,
+    {
+        self.options.allocation.clone()
+    }
+
+    /// Runs a writing operation, and then flushes the tree if its durability
+    /// requires so.
+    async fn write<F, T, E>(&self, operation: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: From<Error>,
+    {
+        let output = operation.await?;
+        self.persist().await?;
+        Ok(output)
+    }
+
+    /// Runs a writing operation within the given timeout, if any, and then
+    /// flushes the tree if its durability requires so.
+    async fn write_within<F, T, E>(
+        &self,
+        timeout: Option<Duration>,
+        operation: F,
+    ) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: From<Error>,
+    {
+        let output = with_timeout(timeout, operation).await?;
+        self.persist().await?;
+        Ok(output)
+    }
+
+    /// Flushes this tree if its durability requires so.
+    async fn persist(&self) -> Result<(), Error> {
+        if self.options.durability == Durability::Flushed {
+            self.shared().storage.flush_async().await?;
+        }
+        Ok(())
+    }
+
+    /// Tests whether the entry with the given key has expired. Blocking.
+    fn is_expired(&self, encoded_key: &[u8], now: u64) -> Result<bool, Error> {
+        match &self.shared().expiry {
+            Some(expiry) => expiry.is_expired(encoded_key, now),
+            None => Ok(false),
+        }
+    }
+
+    /// Records the operation, this tree and, if given, the encoded key in which
+    /// an error happened.
+    fn failed(
+        &self,
+        error: Error,
+        operation: &'static str,
+        encoded_key: Option<&[u8]>,
+    ) -> Error {
+        let error = error.in_operation(operation, &self.shared().storage);
+        match encoded_key {
+            Some(encoded_key) => error.at_key(encoded_key),
+            None => error,
+        }
+    }
+
+    /// Returns the context of the value stored under the given encoded key.
+    fn context<'ctx>(&'ctx self, encoded_key: &'ctx [u8]) -> Context<'ctx> {
+        Context::new(&self.shared().storage, encoded_key)
+    }
+
+    /// Size of the header stored before each value: the envelope, if enabled.
+    fn header_size(&self) -> usize {
+        if self.shared().envelope {
+            envelope::HEADER_SIZE
+        } else {
+            0
+        }
+    }
+
+    /// Makes the stored value of the given encoded key from an encoded value,
+    /// wrapping it in an envelope with the given metadata, if any.
+    fn seal_value<'val>(
+        &self,
+        encoded_key: &[u8],
+        metadata: Option<Metadata>,
+        encoded_value: &'val [u8],
+    ) -> Result<Cow<'val, [u8]>, Error> {
+        let context = self.context(encoded_key);
+        match metadata {
+            Some(metadata) => {
+                let header = envelope::header(metadata);
+                self.shared().codec.seal(context, &header, encoded_value)
+            },
+            None => self.shared().codec.seal(context, &[], encoded_value),
+        }
+    }
+
+    /// Strips a stored value of its envelope and of the transformations of the
+    /// codec.
+    fn open_value<'stored>(
+        &self,
+        encoded_key: &[u8],
+        stored: &'stored [u8],
+    ) -> Result<Cow<'stored, [u8]>, Error> {
+        let context = self.context(encoded_key);
+        self.shared().codec.open(context, stored, self.header_size())
+    }
+
+    /// Decodes a stored value, unwrapping it from its envelope if enabled.
+    fn decode_value(&self, encoded_key: &[u8], bytes: &[u8]) -> Result<V, Error>
+    where
+        for<'de> V: serde::Deserialize<'de>,
+    {
+        let shared = self.shared();
+        let result = self.open_value(encoded_key, bytes).and_then(|payload| {
+            decode_limited(&payload, shared.codec.limit())
+        });
+        result.map_err(|error| {
+            error.in_stage(Stage::ValueDecode).at(&shared.storage, encoded_key)
+        })
+    }
+
+    /// Strips a stored value of its envelope and of the transformations of the
+    /// codec, sharing the stored bytes if the codec does not change them.
+    fn decode_payload(
+        &self,
+        encoded_key: &[u8],
+        stored: IVec,
+    ) -> Result<IVec, Error> {
+        let storage = &self.shared().storage;
+        let payload = self.open_value(encoded_key, &stored).map_err(|error| {
+            error.in_stage(Stage::ValueDecode).at(storage, encoded_key)
+        })?;
+        Ok(match payload {
+            Cow::Borrowed(slice) => {
+                let offset = slice.as_ptr() as usize - stored.as_ptr() as usize;
+                stored.subslice(offset, slice.len())
+            },
+            Cow::Owned(bytes) => IVec::from(bytes),
+        })
+    }
+
+    /// Decodes a stored key.
+    fn decode_key(&self, encoded_key: &[u8]) -> Result<K, Error>
+    where
+        for<'de> K: serde::Deserialize<'de>,
+    {
+        let shared = self.shared();
+        decode_limited(encoded_key, shared.codec.limit()).map_err(|error| {
+            error.in_stage(Stage::KeyDecode).at(&shared.storage, encoded_key)
+        })
+    }
+
+    /// Decodes a stored value along with its metadata. Requires envelopes.
+    fn decode_meta(
+        &self,
+        encoded_key: &[u8],
+        bytes: &[u8],
+    ) -> Result<(V, Metadata), Error>
+    where
+        for<'de> V: serde::Deserialize<'de>,
+    {
+        let shared = self.shared();
+        let result = envelope::open(bytes).and_then(|(metadata, _)| {
+            let payload = self.open_value(encoded_key, bytes)?;
+            Ok((decode_limited(&payload, shared.codec.limit())?, metadata))
+        });
+        result.map_err(|error| {
+            error.in_stage(Stage::ValueDecode).at(&shared.storage, encoded_key)
+        })
+    }
+
+    /// Reads the metadata of a stored value, after checking the value, so that
+    /// a corrupted or forged envelope is not trusted. Requires envelopes.
+    fn open_meta(
+        &self,
+        encoded_key: &[u8],
+        stored: &[u8],
+    ) -> Result<Metadata, Error> {
+        let context = self.context(encoded_key);
+        let shared = self.shared();
+        shared.codec.open_payload(context, stored, envelope::HEADER_SIZE)?;
+        Ok(envelope::open(stored)?.0)
+    }
+
+    /// Makes the stored value of the given encoded key from an encoded value,
+    /// wrapping it in an envelope if enabled, given the current stored value.
+    fn put_value<'val>(
+        &self,
+        encoded_key: &[u8],
+        old: Option<&[u8]>,
+        encoded_value: &'val [u8],
+        stamp: Stamp,
+    ) -> Result<Replace<'val>, Error> {
+        let metadata = if self.shared().envelope {
+            let previous = match old {
+                Some(old) => Some(self.open_meta(encoded_key, old)?),
+                None => None,
+            };
+            Some(Metadata::next(previous, stamp.latest, stamp.now))
+        } else {
+            None
+        };
+        let stored = self.seal_value(encoded_key, metadata, encoded_value)?;
+        Ok(Replace::Put(stored))
+    }
+
+    /// Whether writes need to go through [`Tree::replace_encoded`] rather
+    /// than directly to the storage.
+    fn needs_transaction(&self) -> bool {
+        self.shared().envelope || self.shared().expiry.is_some()
+    }
+
+    /// Atomically replaces the entry with the given encoded key, and sets its
+    /// deadline if the entry is put. The function `make` receives the current
+    /// stored value, unless it is missing or expired, along with the time and
+    /// the latest version of the key, and decides how to replace it. The
+    /// latest version is then updated, a removal taking the next version.
+    /// Returns the previous stored value, unless it was expired. Blocking.
+    fn replace_encoded<'val, F>(
+        &self,
+        encoded_key: &[u8],
+        deadline: Option<u64>,
+        mut make: F,
+    ) -> Result<Option<IVec>, Error>
+    where
+        F: FnMut(Option<&[u8]>, Stamp) -> Result<Replace<'val>, Error>,
+    {
+        self.replace_encoded_in(encoded_key, deadline, &[], |old, stamp, _| {
+            make(old, stamp).map_err(ConflictableTransactionError::Abort)
+        })
+    }
+
+    /// Same as [`Tree::replace_encoded`], but the transaction also includes
+    /// the `extra` trees, whose transactional versions are given to `make`.
+    /// Blocking.
+    fn replace_encoded_in<'val, F>(
+        &self,
+        encoded_key: &[u8],
+        deadline: Option<u64>,
+        extra: &[&sled::Tree],
+        make: F,
+    ) -> Result<Option<IVec>, Error>
+    where
+        F: FnMut(
+            Option<&[u8]>,
+            Stamp,
+            &[TransactionalTree],
+        ) -> TxResult<Replace<'val>>,
+    {
+        Ok(self.replace_encoded_tx(encoded_key, deadline, extra, make)?)
+    }
+
+    /// Same as [`Tree::replace_encoded_in`], but `make` may abort with any
+    /// error type. Blocking.
+    fn replace_encoded_tx<'val, F, E>(
+        &self,
+        encoded_key: &[u8],
+        deadline: Option<u64>,
+        extra: &[&sled::Tree],
+        make: F,
+    ) -> Result<Option<IVec>, TransactionError<E>>
+    where
+        F: FnMut(
+            Option<&[u8]>,
+            Stamp,
+            &[TransactionalTree],
+        ) -> TxResult<Replace<'val>, E>,
+    {
+        let make = RefCell::new(make);
+        let mut trees = vec![&self.shared().storage];
+        trees.extend_from_slice(extra);
+        trees.extend(self.shared().companions());
+
+        trees.as_slice().transaction(|trees| {
+            let storage = &trees[0];
+            let (extra, companions) = trees[1..].split_at(extra.len());
+            let (latest, expiry) = self.shared().companion_views(companions);
+            let now = expiry::now();
+            let stamp = match latest {
+                Some(latest) => Stamp { now, latest: latest.get(encoded_key)? },
+                None => Stamp { now, latest: 0 },
+            };
+
+            let mut old = storage.get(encoded_key)?;
+            if let Some(expiry) = expiry {
+                if old.is_some() && expiry.is_expired(encoded_key, now)? {
+                    old = None;
+                }
+            }
+
+            let replace = (make.borrow_mut())(old.as_deref(), stamp, extra)?;
+            match replace {
+                Replace::Put(encoded_value) => {
+                    storage.insert(encoded_key, &*encoded_value)?;
+                    if let Some(latest) = latest {
+                        latest.put(encoded_key, &encoded_value)?;
+                    }
+                    if let Some(expiry) = expiry {
+                        expiry.set_deadline(encoded_key, deadline)?;
+                    }
+                },
+                Replace::Remove => {
+                    storage.remove(encoded_key)?;
+                    if let (Some(latest), Some(_)) = (latest, &old) {
+                        latest.remove(encoded_key, stamp.latest)?;
+                    }
+                    if let Some(expiry) = expiry {
+                        expiry.set_deadline(encoded_key, None)?;
+                    }
+                },
+                Replace::Keep => (),
+            }
+
+            Ok(old)
+        })
+    }
+
+    /// Atomically inserts the given encoded entries, unless any of their keys
+    /// is already present, in which case nothing is inserted. Returns the
+    /// indices of the present keys, empty on success. Blocking.
+    fn insert_new_encoded(
+        &self,
+        entries: &[(&[u8], &[u8])],
+    ) -> Result<Vec<usize>, Error> {
+        let mut trees = vec![&self.shared().storage];
+        trees.extend(self.shared().companions());
+
+        let present = trees.as_slice().transaction(|trees| {
+            let storage = &trees[0];
+            let (latest, expiry) = self.shared().companion_views(&trees[1..]);
+            let now = expiry::now();
+
+            let mut present = Vec::new();
+            for (index, (encoded_key, _)) in entries.iter().enumerate() {
+                let mut contains = storage.get(encoded_key)?.is_some();
+                if let Some(expiry) = expiry {
+                    if contains && expiry.is_expired(encoded_key, now)? {
+                        contains = false;
+                    }
+                }
+                if contains {
+                    present.push(index);
+                }
+            }
+            if !present.is_empty() {
+                return Ok(present);
+            }
+
+            for (encoded_key, encoded_value) in entries {
+                let stamp = match latest {
+                    Some(latest) => {
+                        Stamp { now, latest: latest.get(encoded_key)? }
+                    },
+                    None => Stamp { now, latest: 0 },
+                };
+                let replace = self
+                    .put_value(encoded_key, None, encoded_value, stamp)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                if let Replace::Put(stored) = replace {
+                    storage.insert(*encoded_key, &*stored)?;
+                    if let Some(latest) = latest {
+                        latest.put(encoded_key, &stored)?;
+                    }
+                }
+                if let Some(expiry) = expiry {
+                    expiry.set_deadline(encoded_key, None)?;
+                }
+            }
+            Ok(present)
+        })?;
+        Ok(present)
+    }
+
+    /// Gets an encoded value, unless it has expired. Blocking.
+    fn get_encoded(&self, encoded_key: &[u8]) -> Result<Option<IVec>, Error> {
+        match self.shared().storage.get(encoded_key)? {
+            Some(encoded_value) => {
+                if self.is_expired(encoded_key, expiry::now())? {
+                    Ok(None)
+                } else {
+                    Ok(Some(encoded_value))
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn get_raw<Q>(
+        &self,
+        key: &Q,
         key_buf: &mut Buffer,
-    ) -> Result<Option<V>, Error> {
-        let encoded_key = key_buf.encode(key)?;
-        let maybe = task::block_in_place(|| self.storage.get(&encoded_key))?;
+    ) -> Result<Option<V>, Error>
+    where
+        for<'de> V: serde::Deserialize<'de>,
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+    {
+        let encoded_key = encode_key(key_buf, key)?;
+        let maybe = task::block_in_place(|| self.get_encoded(encoded_key))?;
         match maybe {
             Some(encoded_value) => {
-                let val = decode(&encoded_value)?;
+                let val = self.decode_value(encoded_key, &encoded_value)?;
                 Ok(Some(val))
             },
             None => Ok(None),
@@ -57,224 +959,1168 @@ where
     }
 
     /// Gets the value associated with the given `key`, returning `None` if key
-    /// is not found. Serializes key using a buffer from a thread-local buffer
-    /// pool.
-    pub async fn get(&self, key: &K) -> Result<Option<V>, Error> {
-        self.get_with(key, buffer::DefaultPool).await
-    }
-
-    /// Gets the value associated with the given `key`, returning `None` if key
-    /// is not found. Uses the given allocation strategy for making buffers.
-    pub async fn get_with<A>(
-        &self,
-        key: &K,
-        mut allocation: A,
-    ) -> Result<Option<V>, Error>
+    /// is not found. Serializes key using a buffer from the allocation of the
+    /// tree.
+    pub async fn get<Q>(&self, key: &Q) -> Result<Option<V>, Error>
     where
-        A: buffer::Allocation,
+        for<'de> V: serde::Deserialize<'de>,
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+        A: buffer::Allocation + Clone,
     {
+        let mut allocation = self.allocation();
         let mut key_buf = allocation.make();
-        let result = self.get_raw(key, &mut key_buf).await;
+        let result = self
+            .get_raw(key, &mut key_buf)
+            .await
+            .map_err(|error| self.failed(error, "get", Some(key_buf.bytes())));
         allocation.save(key_buf);
         result
     }
 
-    async fn insert_raw(
+    async fn get_ref_raw<Q>(
         &self,
-        key: &K,
+        key: &Q,
+        key_buf: &mut Buffer,
+    ) -> Result<Option<Ref<V>>, Error>
+    where
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+    {
+        let encoded_key = encode_key(key_buf, key)?;
+        let maybe = task::block_in_place(|| self.get_encoded(encoded_key))?;
+        match maybe {
+            Some(stored) => {
+                let payload = self.decode_payload(encoded_key, stored)?;
+                Ok(Some(Ref::new(
+                    payload,
+                    self.shared().codec.limit(),
+                    &self.shared().storage,
+                    encoded_key,
+                )))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Gets a guard over the value associated with the given `key`, returning
+    /// `None` if key is not found. The value is decoded by [`Ref::value`],
+    /// borrowing from the guard, which avoids allocating on read paths that
+    /// only inspect some fields. Serializes key using a buffer from the
+    /// allocation of the tree.
+    pub async fn get_ref<Q>(&self, key: &Q) -> Result<Option<Ref<V>>, Error>
+    where
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let mut key_buf = allocation.make();
+        let result = self
+            .get_ref_raw(key, &mut key_buf)
+            .await
+            .map_err(|error| {
+                self.failed(error, "get_ref", Some(key_buf.bytes()))
+            });
+        allocation.save(key_buf);
+        result
+    }
+
+    async fn insert_raw(
+        &self,
+        key: &K,
         val: &V,
         key_buf: &mut Buffer,
         val_buf: &mut Buffer,
-    ) -> Result<Option<V>, Error> {
-        let encoded_key = key_buf.encode(key)?;
-        let encoded_value = val_buf.encode(val)?;
+    ) -> Result<Option<V>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+    {
+        let encoded_key = encode_key(key_buf, key)?;
+        let encoded_value = encode_value(val_buf, val, &self.shared().codec)?;
         let encoded = task::block_in_place(|| {
-            self.storage.insert(&encoded_key, encoded_value)
+            if self.needs_transaction() {
+                self.replace_encoded(encoded_key, None, |old, stamp| {
+                    self.put_value(encoded_key, old, encoded_value, stamp)
+                })
+            } else {
+                let stored = self.seal_value(encoded_key, None, encoded_value)?;
+                Ok(self.shared().storage.insert(encoded_key, &*stored)?)
+            }
         })?;
         match encoded {
-            Some(encoded_val) => Ok(Some(decode(&encoded_val)?)),
+            Some(encoded_val) => {
+                Ok(Some(self.decode_value(encoded_key, &encoded_val)?))
+            },
             None => Ok(None),
         }
     }
 
     /// Inserts key and value returning `None` if key is new, `Some(old_value)`
     /// if the key already exists (and replacing its data). Serializes key and
-    /// value using a buffer from a thread-local buffer pool.
-    pub async fn insert(&self, key: &K, val: &V) -> Result<Option<V>, Error> {
-        self.insert_with(key, val, buffer::DefaultPool).await
+    /// value using a buffer from the allocation of the tree.
+    pub async fn insert(&self, key: &K, val: &V) -> Result<Option<V>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let mut key_buf = allocation.make();
+        let mut val_buf = allocation.make();
+        let result = self
+            .write(self.insert_raw(key, val, &mut key_buf, &mut val_buf))
+            .await
+            .map_err(|error| {
+                self.failed(error, "insert", Some(key_buf.bytes()))
+            });
+        allocation.save(key_buf);
+        allocation.save(val_buf);
+        result
+    }
+
+    /// Inserts key and value, unless the key is already present, in a
+    /// transaction that also includes the `extra` trees, whose transactional
+    /// versions are given to `hook` along with the key and value. Returns
+    /// whether the entry was inserted.
+    async fn insert_new_in_raw<H, E>(
+        &self,
+        key: &K,
+        val: &V,
+        extra: &[&sled::Tree],
+        mut hook: H,
+        key_buf: &mut Buffer,
+        val_buf: &mut Buffer,
+    ) -> Result<bool, TxError<E>>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+        H: FnMut(&K, &V, &[TransactionalTree]) -> TxResult<(), E>,
+    {
+        let encoded_key = encode_key(key_buf, key)?;
+        let encoded_value = encode_value(val_buf, val, &self.shared().codec)?;
+        let mut present = false;
+        task::block_in_place(|| {
+            self.replace_encoded_tx(
+                encoded_key,
+                None,
+                extra,
+                |old, stamp, trees| {
+                    present = old.is_some();
+                    if present {
+                        return Ok(Replace::Keep);
+                    }
+                    hook(key, val, trees).map_err(|error| match error {
+                        ConflictableTransactionError::Abort(error) => {
+                            ConflictableTransactionError::Abort(TxError::Abort(
+                                error,
+                            ))
+                        },
+                        ConflictableTransactionError::Conflict => {
+                            ConflictableTransactionError::Conflict
+                        },
+                        ConflictableTransactionError::Storage(error) => {
+                            ConflictableTransactionError::Storage(error)
+                        },
+                    })?;
+                    self.put_value(encoded_key, None, encoded_value, stamp)
+                        .map_err(|error| {
+                            ConflictableTransactionError::Abort(
+                                TxError::Storage(error),
+                            )
+                        })
+                },
+            )
+        })?;
+        Ok(!present)
+    }
+
+    async fn contains_key_raw<Q>(
+        &self,
+        key: &Q,
+        key_buf: &mut Buffer,
+    ) -> Result<bool, Error>
+    where
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+    {
+        let encoded_key = encode_key(key_buf, key)?;
+        task::block_in_place(|| {
+            let contains = self.shared().storage.contains_key(encoded_key)?;
+            Ok(contains && !self.is_expired(encoded_key, expiry::now())?)
+        })
+    }
+
+    /// Tests if the given key exist. Serializes key using a buffer from the
+    /// allocation of the tree.
+    pub async fn contains_key<Q>(&self, key: &Q) -> Result<bool, Error>
+    where
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let mut key_buf = allocation.make();
+        let result = self
+            .contains_key_raw(key, &mut key_buf)
+            .await
+            .map_err(|error| {
+                self.failed(error, "contains_key", Some(key_buf.bytes()))
+            });
+        allocation.save(key_buf);
+        result
+    }
+
+    async fn remove_raw<Q>(
+        &self,
+        key: &Q,
+        key_buf: &mut Buffer,
+    ) -> Result<Option<V>, Error>
+    where
+        for<'de> V: serde::Deserialize<'de>,
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+    {
+        let encoded_key = encode_key(key_buf, key)?;
+        let encoded = task::block_in_place(|| {
+            if self.needs_transaction() {
+                self.replace_encoded(encoded_key, None, |_, _| {
+                    Ok(Replace::Remove)
+                })
+            } else {
+                Ok(self.shared().storage.remove(encoded_key)?)
+            }
+        })?;
+        match encoded {
+            Some(encoded_val) => {
+                Ok(Some(self.decode_value(encoded_key, &encoded_val)?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the value associated with the given `key`, returning `None` if
+    /// key is not found. Serializes key using a buffer from the allocation of
+    /// the tree.
+    pub async fn remove<Q>(&self, key: &Q) -> Result<Option<V>, Error>
+    where
+        for<'de> V: serde::Deserialize<'de>,
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let mut key_buf = allocation.make();
+        let result = self
+            .write(self.remove_raw(key, &mut key_buf))
+            .await
+            .map_err(|error| {
+                self.failed(error, "remove", Some(key_buf.bytes()))
+            });
+        allocation.save(key_buf);
+        result
+    }
+
+    async fn update_raw<F, E>(
+        &self,
+        key: &K,
+        mut update: F,
+        key_buf: &mut Buffer,
+        val_buf: &mut Buffer,
+    ) -> Result<Option<V>, TxError<E>>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+        F: FnMut(Option<V>) -> Result<Option<V>, E>,
+    {
+        let encoded_key = encode_key(key_buf, key)?;
+        let mut new = None;
+        task::block_in_place(|| {
+            self.replace_encoded_tx(encoded_key, None, &[], |old, stamp, _| {
+                let storage_error = |error| {
+                    ConflictableTransactionError::Abort(TxError::Storage(error))
+                };
+                new = None;
+                let old_val = match old {
+                    Some(old) => Some(
+                        self.decode_value(encoded_key, old)
+                            .map_err(storage_error)?,
+                    ),
+                    None => None,
+                };
+                let val = match update(old_val) {
+                    Ok(Some(val)) => val,
+                    Ok(None) if old.is_some() => return Ok(Replace::Remove),
+                    Ok(None) => return Ok(Replace::Keep),
+                    Err(error) => {
+                        let error = TxError::Abort(error);
+                        return Err(ConflictableTransactionError::Abort(error));
+                    },
+                };
+                let codec = &self.shared().codec;
+                let encoded_value = encode_value(val_buf, &val, codec)
+                    .map_err(storage_error)?;
+                let replace = self
+                    .put_value(encoded_key, old, encoded_value, stamp)
+                    .map_err(storage_error)?;
+                new = Some(val);
+                Ok(replace.into_owned())
+            })
+        })?;
+        Ok(new)
+    }
+
+    /// Atomically updates the value associated with the given `key`, using
+    /// `update` to compute the new value from the current one, or `None` if
+    /// there is none. Returning `None` removes the entry, and returning an
+    /// error aborts the update, giving back the error as [`TxError::Abort`].
+    /// Returns the new value. `update` may run more than once if there are
+    /// concurrent writes. Updating removes any time-to-live of the entry.
+    /// Serializes key and value using a buffer from the allocation of the tree.
+    pub async fn update<F, E>(
+        &self,
+        key: &K,
+        update: F,
+    ) -> Result<Option<V>, TxError<E>>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+        F: FnMut(Option<V>) -> Result<Option<V>, E>,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let mut key_buf = allocation.make();
+        let mut val_buf = allocation.make();
+        let result = self
+            .write(self.update_raw(key, update, &mut key_buf, &mut val_buf))
+            .await
+            .map_err(|error| {
+                error.map_storage(|error| {
+                    self.failed(error, "update", Some(key_buf.bytes()))
+                })
+            });
+        allocation.save(key_buf);
+        allocation.save(val_buf);
+        result
+    }
+
+    fn expiry(&self, operation: &'static str) -> Result<&Expiry, Error> {
+        self.shared().expiry.as_ref().ok_or_else(|| {
+            Unsupported::new(operation, "expiry to be enabled").into()
+        })
+    }
+
+    fn sequence(&self, operation: &'static str) -> Result<&Sequence, Error> {
+        self.shared().sequence.as_ref().ok_or_else(|| {
+            Unsupported::new(operation, "sequences to be enabled").into()
+        })
+    }
+
+    #[cfg(feature = "chacha20poly1305")]
+    fn require_encryption(&self, operation: &'static str) -> Result<(), Error> {
+        if self.shared().codec.is_encrypted() {
+            Ok(())
+        } else {
+            Err(Unsupported::new(operation, "encryption to be enabled").into())
+        }
+    }
+
+    /// Re-encrypts every value not encrypted with the current key of the
+    /// [`Encryption`] settings, returning the number of re-encrypted values.
+    /// This is how keys are rotated: open the tree with the new key as the
+    /// current key and the old key as a decryption key, re-encrypt, and then
+    /// the old key is no longer needed. Each value is re-encrypted atomically,
+    /// but not the whole tree. Requires the tree to be opened with
+    /// [`OpenOptions::encryption`].
+    #[cfg(feature = "chacha20poly1305")]
+    pub async fn reencrypt(&self) -> Result<usize, Error> {
+        self.require_encryption("reencrypt")?;
+        let reencrypted = task::block_in_place(|| {
+            let shared = self.shared();
+            let header_size = self.header_size();
+            reencrypt_storage(&shared.storage, &shared.codec, header_size)
+        });
+        self.write(ready(reencrypted))
+            .await
+            .map_err(|error| self.failed(error, "reencrypt", None))
+    }
+
+    fn require_envelope(&self, operation: &'static str) -> Result<(), Error> {
+        if self.shared().envelope {
+            Ok(())
+        } else {
+            Err(Unsupported::new(operation, "envelopes to be enabled").into())
+        }
+    }
+
+    async fn get_meta_raw(
+        &self,
+        key: &K,
+        key_buf: &mut Buffer,
+    ) -> Result<Option<(V, Metadata)>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Deserialize<'de>,
+    {
+        self.require_envelope("get_meta")?;
+        let encoded_key = encode_key(key_buf, key)?;
+        let maybe = task::block_in_place(|| self.get_encoded(encoded_key))?;
+        match maybe {
+            Some(encoded_value) => {
+                Ok(Some(self.decode_meta(encoded_key, &encoded_value)?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the value associated with the given `key` along with its metadata,
+    /// returning `None` if key is not found. Requires the tree to be opened
+    /// with [`OpenOptions::envelope`]. Serializes key using a buffer from the
+    /// allocation of the tree.
+    pub async fn get_meta(
+        &self,
+        key: &K,
+    ) -> Result<Option<(V, Metadata)>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Deserialize<'de>,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let mut key_buf = allocation.make();
+        let result = self
+            .get_meta_raw(key, &mut key_buf)
+            .await
+            .map_err(|error| {
+                self.failed(error, "get_meta", Some(key_buf.bytes()))
+            });
+        allocation.save(key_buf);
+        result
+    }
+
+    async fn insert_if_version_raw(
+        &self,
+        key: &K,
+        val: &V,
+        expected_version: Option<u64>,
+        key_buf: &mut Buffer,
+        val_buf: &mut Buffer,
+    ) -> Result<Result<Metadata, VersionMismatch>, Error>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+    {
+        self.require_envelope("insert_if_version")?;
+        let encoded_key = encode_key(key_buf, key)?;
+        let encoded_value = encode_value(val_buf, val, &self.shared().codec)?;
+        let mut outcome = Err(VersionMismatch::new(None));
+        task::block_in_place(|| {
+            self.replace_encoded(encoded_key, None, |old, stamp| {
+                let current = match old {
+                    Some(old) => Some(self.open_meta(encoded_key, old)?),
+                    None => None,
+                };
+                let current_version = current.map(|meta| meta.version());
+                if current_version == expected_version {
+                    let metadata =
+                        Metadata::next(current, stamp.latest, stamp.now);
+                    outcome = Ok(metadata);
+                    let stored = self.seal_value(
+                        encoded_key,
+                        Some(metadata),
+                        encoded_value,
+                    )?;
+                    Ok(Replace::Put(stored))
+                } else {
+                    outcome = Err(VersionMismatch::new(current));
+                    Ok(Replace::Keep)
+                }
+            })
+        })?;
+        Ok(outcome)
+    }
+
+    /// Inserts key and value only if the current version of the entry is
+    /// `expected_version`, or, if `expected_version` is `None`, only if the key
+    /// does not exist. Useful for optimistic locking. Returns the metadata of
+    /// the inserted entry, or the metadata of the current entry if the version
+    /// does not match. Inserting this way removes any time-to-live of the
+    /// entry. Requires the tree to be opened with [`OpenOptions::envelope`].
+    /// Serializes key and value using a buffer from the allocation of the tree.
+    pub async fn insert_if_version(
+        &self,
+        key: &K,
+        val: &V,
+        expected_version: Option<u64>,
+    ) -> Result<Result<Metadata, VersionMismatch>, Error>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let mut key_buf = allocation.make();
+        let mut val_buf = allocation.make();
+        let result = self
+            .write(self.insert_if_version_raw(
+                key,
+                val,
+                expected_version,
+                &mut key_buf,
+                &mut val_buf,
+            ))
+            .await
+            .map_err(|error| {
+                self.failed(error, "insert_if_version", Some(key_buf.bytes()))
+            });
+        allocation.save(key_buf);
+        allocation.save(val_buf);
+        result
+    }
+
+    async fn insert_with_ttl_raw(
+        &self,
+        key: &K,
+        val: &V,
+        ttl: Duration,
+        key_buf: &mut Buffer,
+        val_buf: &mut Buffer,
+    ) -> Result<Option<V>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+    {
+        self.expiry("insert_with_ttl")?;
+        let encoded_key = encode_key(key_buf, key)?;
+        let encoded_value = encode_value(val_buf, val, &self.shared().codec)?;
+        let deadline = expiry::deadline_after(ttl);
+        let encoded = task::block_in_place(|| {
+            self.replace_encoded(encoded_key, Some(deadline), |old, stamp| {
+                self.put_value(encoded_key, old, encoded_value, stamp)
+            })
+        })?;
+        match encoded {
+            Some(encoded_val) => {
+                Ok(Some(self.decode_value(encoded_key, &encoded_val)?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Inserts key and value, which expire after the given time-to-live,
+    /// returning `None` if key is new, `Some(old_value)` if the key already
+    /// exists (and replacing its data). Expired entries are invisible to reads
+    /// and are removed by [`Tree::sweep_expired`]. Requires the tree to be
+    /// opened with [`OpenOptions::expiry`]. Serializes key and value using a
+    /// buffer from the allocation of the tree.
+    pub async fn insert_with_ttl(
+        &self,
+        key: &K,
+        val: &V,
+        ttl: Duration,
+    ) -> Result<Option<V>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let mut key_buf = allocation.make();
+        let mut val_buf = allocation.make();
+        let result = self
+            .write(self.insert_with_ttl_raw(
+                key,
+                val,
+                ttl,
+                &mut key_buf,
+                &mut val_buf,
+            ))
+            .await
+            .map_err(|error| {
+                self.failed(error, "insert_with_ttl", Some(key_buf.bytes()))
+            });
+        allocation.save(key_buf);
+        allocation.save(val_buf);
+        result
+    }
+
+    /// Removes every expired entry, returning the number of removed entries.
+    /// Requires the tree to be opened with [`OpenOptions::expiry`].
+    pub async fn sweep_expired(&self) -> Result<usize, Error> {
+        let expiry = self.expiry("sweep_expired")?;
+        let storage = &self.shared().storage;
+        let swept = task::block_in_place(|| expiry.sweep(storage));
+        self.write(ready(swept))
+            .await
+            .map_err(|error| self.failed(error, "sweep_expired", None))
+    }
+
+    /// Spawns a background task that removes expired entries once every
+    /// `period`. The task runs until aborted through the returned handle, or
+    /// until a sweep fails, in which case the task finishes with the error.
+    /// Requires the tree to be opened with [`OpenOptions::expiry`], and must
+    /// be called from within a multi-threaded tokio runtime.
+    pub fn spawn_expiry_sweeper(
+        &self,
+        period: Duration,
+    ) -> Result<JoinHandle<Error>, Error> {
+        let expiry = self.expiry("spawn_expiry_sweeper")?.clone();
+        let storage = self.shared().storage.clone();
+        Ok(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if let Err(error) =
+                    task::block_in_place(|| expiry.sweep(&storage))
+                {
+                    break error.in_operation("sweep_expired", &storage);
+                }
+            }
+        }))
+    }
+
+    fn get_many_raw<'key, I, F>(
+        &self,
+        keys: I,
+        key_buf: &mut Buffer,
+        mut visitor: F,
+    ) -> Result<(), Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Deserialize<'de>,
+        I: IntoIterator<Item = &'key K>,
+        K: 'key,
+        F: FnMut(&'key K, Option<V>),
+    {
+        task::block_in_place(|| {
+            for key in keys {
+                let encoded_key = encode_key(key_buf, key)?;
+                let maybe = match self.get_encoded(encoded_key)? {
+                    Some(encoded_value) => {
+                        Some(self.decode_value(encoded_key, &encoded_value)?)
+                    },
+                    None => None,
+                };
+                visitor(key, maybe);
+            }
+            Ok(())
+        })
+    }
+
+    /// Gets the values associated with each of the given `keys`, in the same
+    /// order as the keys, with `None` for keys that are not found. All lookups
+    /// happen in a single blocking section, reusing a single key buffer from
+    /// the allocation of the tree.
+    pub async fn get_many<'key, I>(
+        &self,
+        keys: I,
+    ) -> Result<Vec<Option<V>>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Deserialize<'de>,
+        I: IntoIterator<Item = &'key K>,
+        K: 'key,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let keys = keys.into_iter();
+        let mut values = Vec::with_capacity(keys.size_hint().0);
+        let mut key_buf = allocation.make();
+        let result = self
+            .get_many_raw(keys, &mut key_buf, |_, maybe| values.push(maybe))
+            .map_err(|error| self.failed(error, "get_many", None));
+        allocation.save(key_buf);
+        result.map(|()| values)
+    }
+
+    /// Gets the values associated with each of the given `keys`, collected into
+    /// a map. Keys that are not found are not present in the map. All lookups
+    /// happen in a single blocking section, reusing a single key buffer from
+    /// the allocation of the tree.
+    pub async fn get_many_map<'key, I>(
+        &self,
+        keys: I,
+    ) -> Result<BTreeMap<K, V>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Deserialize<'de>,
+        I: IntoIterator<Item = &'key K>,
+        K: 'key + Ord + Clone,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let mut map = BTreeMap::new();
+        let mut key_buf = allocation.make();
+        let result = self
+            .get_many_raw(keys, &mut key_buf, |key, maybe| {
+                if let Some(val) = maybe {
+                    map.insert(key.clone(), val);
+                }
+            })
+            .map_err(|error| self.failed(error, "get_many_map", None));
+        allocation.save(key_buf);
+        result.map(|()| map)
+    }
+
+    /// Returns a stream over every entry, in the order of the encoded keys.
+    /// Each entry yields its own result, see [`Iter`].
+    pub fn iter(&self) -> Iter<K, V, A>
+    where
+        for<'de> K: serde::Deserialize<'de>,
+        for<'de> V: serde::Deserialize<'de>,
+        A: Clone,
+    {
+        Iter::new(self.owned(), self.shared().storage.iter(), "iter")
+    }
+
+    fn range_raw<R>(
+        &self,
+        range: R,
+        start_buf: &mut Buffer,
+        end_buf: &mut Buffer,
+    ) -> Result<Iter<K, V, A>, Error>
+    where
+        for<'de> K: serde::Serialize + serde::Deserialize<'de>,
+        for<'de> V: serde::Deserialize<'de>,
+        R: RangeBounds<K>,
+        A: Clone,
+    {
+        let start = encode_bound(range.start_bound(), start_buf)?;
+        let end = encode_bound(range.end_bound(), end_buf)?;
+        let entries = self.shared().storage.range::<&[u8], _>((start, end));
+        Ok(Iter::new(self.owned(), entries, "range"))
+    }
+
+    /// Returns a stream over every entry whose key is in the given `range`. The
+    /// range is matched against the encoded keys, as in [`Tree::remove_range`].
+    /// Each entry yields its own result, see [`Iter`]. Serializes bounds using
+    /// buffers from the allocation of the tree.
+    pub fn range<R>(&self, range: R) -> Result<Iter<K, V, A>, Error>
+    where
+        for<'de> K: serde::Serialize + serde::Deserialize<'de>,
+        for<'de> V: serde::Deserialize<'de>,
+        R: RangeBounds<K>,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let mut start_buf = allocation.make();
+        let mut end_buf = allocation.make();
+        let result = self
+            .range_raw(range, &mut start_buf, &mut end_buf)
+            .map_err(|error| self.failed(error, "range", None));
+        allocation.save(start_buf);
+        allocation.save(end_buf);
+        result
+    }
+
+    fn remove_batched<F>(
+        &self,
+        entries: sled::Iter,
+        mut should_remove: F,
+    ) -> Result<usize, Error>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool, Error>,
+    {
+        task::block_in_place(|| {
+            let now = expiry::now();
+            let mut removed = 0;
+            let mut batch = Vec::new();
+
+            for entry in entries {
+                let (encoded_key, stored) = entry?;
+                if self.is_expired(&encoded_key, now)? {
+                    batch.push((encoded_key, stored, true));
+                } else if should_remove(&encoded_key, &stored)? {
+                    batch.push((encoded_key, stored, false));
+                }
+                if batch.len() == REMOVE_BATCH_SIZE {
+                    removed += self.remove_batch(&batch)?;
+                    batch.clear();
+                }
+            }
+
+            if !batch.is_empty() {
+                removed += self.remove_batch(&batch)?;
+            }
+
+            Ok(removed)
+        })
+    }
+
+    /// Atomically removes the given entries, along with their deadlines,
+    /// skipping those whose stored value changed since it was scanned, and
+    /// those scanned as expired which are no longer expired. Each entry is an
+    /// encoded key, the scanned stored value, and whether it was expired.
+    /// Returns the number of removed entries which were not expired.
+    /// Blocking.
+    fn remove_batch(
+        &self,
+        batch: &[(IVec, IVec, bool)],
+    ) -> Result<usize, Error> {
+        let mut trees = vec![&self.shared().storage];
+        trees.extend(self.shared().companions());
+        let removed = trees.as_slice().transaction(|trees| -> TxResult<_> {
+            let storage = &trees[0];
+            let (latest, expiry) = self.shared().companion_views(&trees[1..]);
+            let now = expiry::now();
+            let mut removed = 0;
+            for (encoded_key, stored, expired) in batch {
+                if storage.get(encoded_key)?.as_ref() != Some(stored) {
+                    continue;
+                }
+                if let Some(expiry) = expiry {
+                    if *expired && !expiry.is_expired(encoded_key, now)? {
+                        continue;
+                    }
+                    expiry.set_deadline(encoded_key, None)?;
+                }
+                storage.remove(encoded_key)?;
+                if let Some(latest) = latest {
+                    latest.remove(encoded_key, latest.get(encoded_key)?)?;
+                }
+                if !expired {
+                    removed += 1;
+                }
+            }
+            Ok(removed)
+        })?;
+        Ok(removed)
+    }
+
+    async fn remove_range_raw<R>(
+        &self,
+        range: R,
+        start_buf: &mut Buffer,
+        end_buf: &mut Buffer,
+    ) -> Result<usize, Error>
+    where
+        K: serde::Serialize,
+        R: RangeBounds<K>,
+    {
+        let start = encode_bound(range.start_bound(), start_buf)?;
+        let end = encode_bound(range.end_bound(), end_buf)?;
+        let entries = self.shared().storage.range::<&[u8], _>((start, end));
+        self.remove_batched(entries, |_, _| Ok(true))
+    }
+
+    /// Removes every entry whose key is in the given `range`, returning the
+    /// number of removed entries. Removal is applied in atomic batches of
+    /// bounded size, so the whole range is not removed atomically, and entries
+    /// changed concurrently after being scanned are left in place and not
+    /// counted. The range is matched against the encoded keys, so it follows
+    /// the key's encoding order, which is the same as the key's `Ord` for
+    /// unsigned integers, but not for e.g. strings and signed integers.
+    /// Expired entries in the range are removed too, but are not counted.
+    /// Serializes bounds using buffers from the allocation of the tree.
+    pub async fn remove_range<R>(&self, range: R) -> Result<usize, Error>
+    where
+        K: serde::Serialize,
+        R: RangeBounds<K>,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.allocation();
+        let mut start_buf = allocation.make();
+        let mut end_buf = allocation.make();
+        let result = self
+            .write(self.remove_range_raw(range, &mut start_buf, &mut end_buf))
+            .await
+            .map_err(|error| self.failed(error, "remove_range", None));
+        allocation.save(start_buf);
+        allocation.save(end_buf);
+        result
+    }
+
+    /// Keeps only the entries for which `predicate` returns `true`, removing
+    /// all others and returning the number of removed entries. Removal is
+    /// applied in atomic batches of bounded size, so the whole operation is not
+    /// atomic. An entry changed concurrently after being visited is left in
+    /// place and not counted. Expired entries are removed without being
+    /// visited, and are not counted.
+    pub async fn retain<F>(&self, mut predicate: F) -> Result<usize, Error>
+    where
+        for<'de> K: serde::Deserialize<'de>,
+        for<'de> V: serde::Deserialize<'de>,
+        F: FnMut(&K, &V) -> bool,
+    {
+        let removed = self.remove_batched(
+            self.shared().storage.iter(),
+            |encoded_key, encoded_val| {
+                let key = self.decode_key(encoded_key)?;
+                let val = self.decode_value(encoded_key, encoded_val)?;
+                Ok(!predicate(&key, &val))
+            },
+        );
+        self.write(ready(removed))
+            .await
+            .map_err(|error| self.failed(error, "retain", None))
+    }
+
+    /// Moves an invalid entry to the quarantine tree, unless it changed since
+    /// it was scanned. Returns whether the entry was moved. Blocking.
+    fn quarantine_entry(
+        &self,
+        quarantine: &sled::Tree,
+        encoded_key: &[u8],
+        stored: &[u8],
+    ) -> Result<bool, Error> {
+        let mut moved = false;
+        self.replace_encoded_in(
+            encoded_key,
+            None,
+            &[quarantine],
+            |old, _, trees| -> TxResult<_> {
+                moved = old == Some(stored);
+                if moved {
+                    trees[0].insert(encoded_key, stored)?;
+                    Ok(Replace::Remove)
+                } else {
+                    Ok(Replace::Keep)
+                }
+            },
+        )?;
+        Ok(moved)
     }
 
-    /// Inserts key and value returning `None` if key is new, `Some(old_value)`
-    /// if the key already exists (and replacing its data). Uses the given
-    /// allocation strategy for making buffers.
-    pub async fn insert_with<A>(
+    fn verify_raw(
         &self,
-        key: &K,
-        val: &V,
-        mut allocation: A,
-    ) -> Result<Option<V>, Error>
+        quarantine: Option<&sled::Tree>,
+    ) -> Result<VerifyReport, Error>
     where
-        A: buffer::Allocation,
+        for<'de> K: serde::Serialize + serde::Deserialize<'de>,
+        for<'de> V: serde::Deserialize<'de>,
     {
-        let mut key_buf = allocation.make();
-        let mut val_buf = allocation.make();
-        let result =
-            self.insert_raw(key, val, &mut key_buf, &mut val_buf).await;
-        allocation.save(key_buf);
-        allocation.save(val_buf);
-        result
-    }
+        task::block_in_place(|| {
+            let mut report = VerifyReport::default();
+            let mut key_buf = Buffer::default();
 
-    async fn contains_key_raw(
-        &self,
-        key: &K,
-        key_buf: &mut Buffer,
-    ) -> Result<bool, Error> {
-        let encoded_key = key_buf.encode(key)?;
-        let result =
-            task::block_in_place(|| self.storage.contains_key(&encoded_key))?;
-        Ok(result)
+            for entry in self.shared().storage.iter() {
+                let (encoded_key, stored) = entry?;
+                report.count();
+                let result = self.decode_key(&encoded_key).and_then(|key| {
+                    self.decode_value(&encoded_key, &stored)?;
+                    Ok(key)
+                });
+                match result {
+                    Ok(key) => {
+                        let reencoded = key_buf.encode(&key).ok();
+                        if reencoded != Some(&encoded_key[..]) {
+                            report.non_canonical_key(encoded_key);
+                        }
+                    },
+                    Err(error) => {
+                        let quarantined = match quarantine {
+                            Some(quarantine) => self.quarantine_entry(
+                                quarantine,
+                                &encoded_key,
+                                &stored,
+                            )?,
+                            None => false,
+                        };
+                        report.invalid(encoded_key, stored, error, quarantined);
+                    },
+                }
+            }
+
+            Ok(report)
+        })
     }
 
-    /// Tests if the given key exist. Serializes key using a buffer from a
-    /// thread-local buffer pool.
-    pub async fn contains_key(&self, key: &K) -> Result<bool, Error> {
-        self.contains_key_with(key, buffer::DefaultPool).await
+    /// Scans every entry, decoding both key and value, and reports the
+    /// entries which cannot be decoded, the values which do not match their
+    /// checksums, and the keys which do not encode back into their stored
+    /// bytes. Expired entries not yet swept are also verified.
+    pub async fn verify(&self) -> Result<VerifyReport, Error>
+    where
+        for<'de> K: serde::Serialize + serde::Deserialize<'de>,
+        for<'de> V: serde::Deserialize<'de>,
+    {
+        self.verify_raw(None)
+            .map_err(|error| self.failed(error, "verify", None))
     }
 
-    /// Tests if the given key exist. Uses the given allocation strategy for
-    /// making buffers.
-    pub async fn contains_key_with<A>(
-        &self,
-        key: &K,
-        mut allocation: A,
-    ) -> Result<bool, Error>
+    /// Same as [`Tree::verify`], but also moves the entries which cannot be
+    /// decoded, including checksum mismatches, from this tree into a
+    /// quarantine tree, keeping their raw bytes. The quarantine tree is a
+    /// companion of this tree in `db`, which must be the database this tree
+    /// was opened from. Each entry is moved atomically, but not the whole
+    /// tree. Non-canonical keys are only reported.
+    pub async fn repair(&self, db: &sled::Db) -> Result<VerifyReport, Error>
     where
-        A: buffer::Allocation,
+        for<'de> K: serde::Serialize + serde::Deserialize<'de>,
+        for<'de> V: serde::Deserialize<'de>,
     {
-        let mut key_buf = allocation.make();
-        let result = self.contains_key_raw(key, &mut key_buf).await;
-        allocation.save(key_buf);
-        result
+        let name = companion_name(&self.shared().storage.name(), "quarantine");
+        let report = task::block_in_place(|| db.open_tree(name))
+            .map_err(Error::from)
+            .and_then(|quarantine| self.verify_raw(Some(&quarantine)));
+        self.write(ready(report))
+            .await
+            .map_err(|error| self.failed(error, "repair", None))
     }
 
-    async fn remove_raw(
-        &self,
-        key: &K,
-        key_buf: &mut Buffer,
-    ) -> Result<Option<V>, Error> {
-        let encoded_key = key_buf.encode(key)?;
-        match task::block_in_place(|| self.storage.remove(&encoded_key))? {
-            Some(encoded_val) => Ok(Some(decode(&encoded_val)?)),
-            None => Ok(None),
-        }
+    /// Returns the next number of the sequence of this tree, starting at 0.
+    /// Numbers are strictly increasing and never repeated, even across
+    /// crashes, and have no gaps unless the tree is opened with a block size
+    /// greater than 1. The sequence is shared by every instance of this tree
+    /// opened from the same database, and clones of a handle draw numbers
+    /// from the same block. Requires the tree to be opened with
+    /// [`OpenOptions::sequence`].
+    pub async fn next_sequence(&self) -> Result<u64, Error> {
+        let sequence = self.sequence("next_sequence")?;
+        task::block_in_place(|| sequence.next())
+            .map_err(|error| self.failed(error, "next_sequence", None))
     }
 
-    /// Removes the value associated with the given `key`, returning `None` if
-    /// key is not found. Serializes key using a buffer from a thread-local
-    /// buffer pool.
-    pub async fn remove(&self, key: &K) -> Result<Option<V>, Error> {
-        self.remove_with(key, buffer::DefaultPool).await
+    /// Returns a source of IDs drawing numbers from the sequence of this tree,
+    /// see [`IdBuilder::id_source`], so generated IDs are dense numbers of
+    /// this tree. Requires the tree to be opened with
+    /// [`OpenOptions::sequence`].
+    pub fn sequence_ids(&self) -> Result<SequenceIds, Error> {
+        let sequence = self
+            .sequence("sequence_ids")
+            .map_err(|error| self.failed(error, "sequence_ids", None))?;
+        Ok(SequenceIds::new(sequence.clone()))
     }
 
-    /// Removes the value associated with the given `key`, returning `None` if
-    /// key is not found. Uses the given allocation strategy for making buffers.
-    pub async fn remove_with<A>(
-        &self,
-        key: &K,
-        mut allocation: A,
-    ) -> Result<Option<V>, Error>
+    /// Encodes the given ID as a short, opaque string with the encoder of this
+    /// tree, see [`OpenOptions::id_encoder`].
+    pub fn encode_id(&self, id: Id) -> String {
+        self.shared().id_encoder.encode(id)
+    }
+
+    /// Decodes a string produced by [`Tree::encode_id`] back into the ID.
+    /// Produces [`ErrorKind::InvalidId`](crate::error::ErrorKind) for any
+    /// other string.
+    pub fn decode_id(&self, input: &str) -> Result<Id, Error> {
+        self.shared().id_encoder.decode(input)
+    }
+
+    /// Maps a string, e.g. taken from a URL, back to the key generated by
+    /// [`IdBuilder::encoded_id_maker`], checking that the encoder of this tree
+    /// produced it. Produces
+    /// [`ErrorKind::InvalidId`](crate::error::ErrorKind) for any other string.
+    pub fn parse_encoded_key(&self, input: &str) -> Result<K, Error>
     where
-        A: buffer::Allocation,
+        K: From<String>,
     {
-        let mut key_buf = allocation.make();
-        let result = self.remove_raw(key, &mut key_buf).await;
-        allocation.save(key_buf);
-        result
+        self.decode_id(input)?;
+        Ok(K::from(input.to_owned()))
     }
 
     /// Creates a builder for an ID generator.
     ///
     /// An ID generator tries to generate a new ID as a key of an entry, and
     /// when successful, inserts the key with a value. First of all, it
-    /// generates an integer, and then it uses the given function `make_id` to
-    /// produce a key. When an actual such key is indeed new, the method uses
-    /// another function, `make_data`, to produce a value associated with the
-    /// key. With a key-value pair, it inserts them in the tree.
+    /// generates an ID from its source, an integer from the database by
+    /// default (see [`IdBuilder::id_source`]), and then it uses the given
+    /// function `make_id` to produce a key. When an actual such key is indeed
+    /// new, the method uses another function, `make_data`, to produce a value
+    /// associated with the key. With a key-value pair, it inserts them in the
+    /// tree.
     ///
-    /// Serializes key and value using thread-local buffer by default, but
-    /// allows passing a custom allocation. Also by default, all errors could
-    /// only be [`Error`], but that behaviour is configurable via
+    /// Serializes key and value using the allocation of the tree by default,
+    /// but allows passing a custom allocation. Also by default, all errors
+    /// could only be [`Error`], but that behaviour is configurable via
     /// [`IdBuilder::error_conversor`];
-    pub fn id_builder(
-        &self,
-    ) -> IdBuilder<K, V, buffer::DefaultPool, fn(Error) -> Error, (), ()> {
+    pub fn id_builder(&self) -> DefaultIdBuilder<'_, K, V, A>
+    where
+        A: Clone,
+    {
         IdBuilder::new(self)
     }
 }
 
-impl<K, V> Clone for Tree<K, V>
+impl<K, V, A, S> Clone for Tree<K, V, A, S>
 where
-    for<'de> K: serde::Serialize + serde::Deserialize<'de>,
-    for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+    A: Clone,
+    S: Clone,
 {
     fn clone(&self) -> Self {
-        Self { _marker: self._marker, storage: self.storage.clone() }
+        Self {
+            _marker: self._marker,
+            shared: self.shared.clone(),
+            options: self.options.clone(),
+        }
     }
 }
 
-impl<K, V> fmt::Debug for Tree<K, V>
+impl<K, V, A, S> fmt::Debug for Tree<K, V, A, S>
 where
-    for<'de> K: serde::Serialize + serde::Deserialize<'de>,
-    for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+    A: fmt::Debug,
+    S: Borrow<Shared>,
 {
     fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
-        fmtr.debug_struct("Tree").field("storage", &self.storage).finish()
+        let shared = self.shared();
+        fmtr.debug_struct("Tree")
+            .field("storage", &shared.storage)
+            .field("expiry", &shared.expiry)
+            .field("envelope", &shared.envelope)
+            .field("sequence", &shared.sequence)
+            .field("id_encoder", &shared.id_encoder)
+            .field("codec", &shared.codec)
+            .field("options", &self.options)
+            .finish()
     }
 }
 
+/// An ID generator builder, as returned by [`Tree::id_builder`], before any
+/// configuration.
+pub type DefaultIdBuilder<'tree, K, V, A = buffer::DefaultPool> =
+    IdBuilder<'tree, K, V, A, fn(Error) -> Error, (), (), DbIds, A>;
+
 /// An ID generator builder. See [`Tree::id_builder`] for more details.
 #[derive(Debug, Clone)]
-pub struct IdBuilder<'tree, K, V, A, FE, FK, FV>
-where
-    for<'de> K: serde::Serialize + serde::Deserialize<'de>,
-    for<'de> V: serde::Serialize + serde::Deserialize<'de>,
-{
-    tree: &'tree Tree<K, V>,
+pub struct IdBuilder<
+    'tree,
+    K,
+    V,
+    A,
+    FE,
+    FK,
+    FV,
+    S = DbIds,
+    TA = buffer::DefaultPool,
+> {
+    tree: TreeRef<'tree, K, V, TA>,
     allocation: A,
     make_error: FE,
     make_id: FK,
     make_data: FV,
+    source: S,
+    timeout: Option<Duration>,
 }
 
-impl<'tree, K, V>
-    IdBuilder<'tree, K, V, buffer::DefaultPool, fn(Error) -> Error, (), ()>
+impl<'tree, K, V, A> DefaultIdBuilder<'tree, K, V, A>
 where
-    for<'de> K: serde::Serialize + serde::Deserialize<'de>,
-    for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+    A: Clone,
 {
-    fn new(tree: &'tree Tree<K, V>) -> Self {
+    fn new<S>(tree: &'tree Tree<K, V, A, S>) -> Self
+    where
+        S: Borrow<Shared>,
+    {
         Self {
-            tree,
-            allocation: buffer::DefaultPool,
+            tree: tree.with_options(tree.options.clone()),
+            allocation: tree.allocation(),
             make_error: |error| error,
             make_id: (),
             make_data: (),
+            source: DbIds,
+            timeout: tree.options.timeout,
         }
     }
 }
 
-impl<'tree, K, V, A, FE, FK, FV> IdBuilder<'tree, K, V, A, FE, FK, FV>
-where
-    for<'de> K: serde::Serialize + serde::Deserialize<'de>,
-    for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+#[allow(clippy::type_complexity)]
+impl<'tree, K, V, A, FE, FK, FV, S, TA>
+    IdBuilder<'tree, K, V, A, FE, FK, FV, S, TA>
 {
     /// Changes the serialization buffer allocation. By default, the builder
-    /// would use a thread-local pool.
+    /// would use the allocation of the tree.
     pub fn allocation<A0>(
         self,
         allocation: A0,
-    ) -> IdBuilder<'tree, K, V, A0, FE, FK, FV>
+    ) -> IdBuilder<'tree, K, V, A0, FE, FK, FV, S, TA>
     where
         A0: buffer::Allocation,
     {
@@ -284,6 +2130,8 @@ where
             make_error: self.make_error,
             make_id: self.make_id,
             make_data: self.make_data,
+            source: self.source,
+            timeout: self.timeout,
         }
     }
 
@@ -291,7 +2139,7 @@ where
     pub fn error_conversor<FE0, E>(
         self,
         make_error: FE0,
-    ) -> IdBuilder<'tree, K, V, A, FE0, FK, FV>
+    ) -> IdBuilder<'tree, K, V, A, FE0, FK, FV, S, TA>
     where
         FE0: FnOnce(Error) -> E,
     {
@@ -301,6 +2149,8 @@ where
             make_error,
             make_id: self.make_id,
             make_data: self.make_data,
+            source: self.source,
+            timeout: self.timeout,
         }
     }
 
@@ -308,21 +2158,61 @@ where
     /// trait.
     pub fn error_from<E>(
         self,
-    ) -> IdBuilder<'tree, K, V, A, impl FnOnce(Error) -> E, FK, FV>
+    ) -> IdBuilder<'tree, K, V, A, impl FnOnce(Error) -> E, FK, FV, S, TA>
     where
         E: From<Error>,
     {
         self.error_conversor(E::from)
     }
 
+    /// Sets a timeout for the generation, after which it fails with
+    /// [`ErrorKind::Timeout`](crate::error::ErrorKind). By default, the
+    /// timeout of the tree is used, see [`CallOptions::timeout`]. A generation
+    /// which timed out has not inserted anything, see [`IdBuilder::generate`].
+    pub fn timeout(self, duration: Duration) -> Self {
+        IdBuilder { timeout: Some(duration), ..self }
+    }
+
+    /// Changes the source of the candidate IDs given to the "id maker". By
+    /// default, IDs are generated by the database, see [`DbIds`]. Since the
+    /// "id maker" receives the IDs of the source, it must be set again.
+    pub fn id_source<S0>(
+        self,
+        source: S0,
+    ) -> IdBuilder<'tree, K, V, A, FE, (), FV, S0, TA>
+    where
+        S0: IdSource,
+    {
+        IdBuilder {
+            tree: self.tree,
+            allocation: self.allocation,
+            make_error: self.make_error,
+            make_id: (),
+            make_data: self.make_data,
+            source,
+            timeout: self.timeout,
+        }
+    }
+
     /// Sets the given function as the "id maker", a function that CANNOT fail
     /// and is SYNChronous.
     pub fn id_maker<FK0, E>(
         self,
         mut make_id: FK0,
-    ) -> IdBuilder<'tree, K, V, A, FE, impl FnMut(Id) -> Ready<Result<K, E>>, FV>
+    ) -> IdBuilder<
+        'tree,
+        K,
+        V,
+        A,
+        FE,
+        impl FnMut(S::Id) -> Ready<Result<K, E>>,
+        FV,
+        S,
+        TA,
+    >
     where
-        FK0: FnMut(Id) -> K,
+        S: IdSource,
+        FK0: FnMut(S::Id) -> K,
     {
         self.fallible_async_id_maker(move |bits| ready(Ok(make_id(bits))))
     }
@@ -332,9 +2222,20 @@ where
     pub fn fallible_id_maker<FK0, E>(
         self,
         mut make_id: FK0,
-    ) -> IdBuilder<'tree, K, V, A, FE, impl FnMut(Id) -> Ready<Result<K, E>>, FV>
+    ) -> IdBuilder<
+        'tree,
+        K,
+        V,
+        A,
+        FE,
+        impl FnMut(S::Id) -> Ready<Result<K, E>>,
+        FV,
+        S,
+        TA,
+    >
     where
-        FK0: FnMut(Id) -> Result<K, E>,
+        S: IdSource,
+        FK0: FnMut(S::Id) -> Result<K, E>,
     {
         self.fallible_async_id_maker(move |bits| ready(make_id(bits)))
     }
@@ -350,11 +2251,14 @@ where
         V,
         A,
         FE,
-        impl FnMut(Id) -> Map<AK, fn(K) -> Result<K, E>>,
+        impl FnMut(S::Id) -> Map<AK, fn(K) -> Result<K, E>>,
         FV,
+        S,
+        TA,
     >
     where
-        FK0: FnMut(Id) -> AK,
+        S: IdSource,
+        FK0: FnMut(S::Id) -> AK,
         AK: Future<Output = K>,
     {
         self.fallible_async_id_maker(move |bits| {
@@ -367,9 +2271,10 @@ where
     pub fn fallible_async_id_maker<FK0, AK, E>(
         self,
         make_id: FK0,
-    ) -> IdBuilder<'tree, K, V, A, FE, FK0, FV>
+    ) -> IdBuilder<'tree, K, V, A, FE, FK0, FV, S, TA>
     where
-        FK0: FnMut(Id) -> AK,
+        S: IdSource,
+        FK0: FnMut(S::Id) -> AK,
         AK: Future<Output = Result<K, E>>,
     {
         IdBuilder {
@@ -378,6 +2283,8 @@ where
             make_error: self.make_error,
             make_id,
             make_data: self.make_data,
+            source: self.source,
+            timeout: self.timeout,
         }
     }
 
@@ -386,7 +2293,17 @@ where
     pub fn data_maker<FV0, E>(
         self,
         make_data: FV0,
-    ) -> IdBuilder<'tree, K, V, A, FE, FK, impl FnOnce(&K) -> Ready<Result<V, E>>>
+    ) -> IdBuilder<
+        'tree,
+        K,
+        V,
+        A,
+        FE,
+        FK,
+        impl FnOnce(&K) -> Ready<Result<V, E>>,
+        S,
+        TA,
+    >
     where
         FV0: FnOnce(&K) -> V,
     {
@@ -398,7 +2315,17 @@ where
     pub fn fallible_data_maker<FV0, E>(
         self,
         make_data: FV0,
-    ) -> IdBuilder<'tree, K, V, A, FE, FK, impl FnOnce(&K) -> Ready<Result<V, E>>>
+    ) -> IdBuilder<
+        'tree,
+        K,
+        V,
+        A,
+        FE,
+        FK,
+        impl FnOnce(&K) -> Ready<Result<V, E>>,
+        S,
+        TA,
+    >
     where
         FV0: FnOnce(&K) -> Result<V, E>,
     {
@@ -418,6 +2345,8 @@ where
         FE,
         FK,
         impl FnOnce(&K) -> Map<AV, fn(V) -> Result<V, E>>,
+        S,
+        TA,
     >
     where
         FV0: FnOnce(&K) -> AV,
@@ -433,7 +2362,7 @@ where
     pub fn fallible_async_data_maker<FV0, AV, E>(
         self,
         make_data: FV0,
-    ) -> IdBuilder<'tree, K, V, A, FE, FK, FV0>
+    ) -> IdBuilder<'tree, K, V, A, FE, FK, FV0, S, TA>
     where
         FV0: FnOnce(&K) -> AV,
         AV: Future<Output = Result<V, E>>,
@@ -444,65 +2373,673 @@ where
             make_error: self.make_error,
             make_id: self.make_id,
             make_data,
+            source: self.source,
+            timeout: self.timeout,
         }
     }
 
+    /// Splits the builder into the part which generates candidate keys, the
+    /// allocation, the "error conversor" and the "data maker", so that the
+    /// buffers of the allocation can be held while generating.
+    fn into_parts(
+        self,
+    ) -> (IdBuilder<'tree, K, V, (), (), FK, (), S, TA>, A, FE, FV) {
+        let ids = IdBuilder {
+            tree: self.tree,
+            allocation: (),
+            make_error: (),
+            make_id: self.make_id,
+            make_data: (),
+            source: self.source,
+            timeout: self.timeout,
+        };
+        (ids, self.allocation, self.make_error, self.make_data)
+    }
+
     /// Generates the ID whenever the builder is ready. The builder is ready if
     /// all of "id maker" and "data maker". Meanwhile, "allocator" and "error
     /// conversor" have a default value.
-    pub async fn generate<E, AK, AV>(
-        mut self,
+    ///
+    /// The entry is only inserted if its key is still absent, so an entry
+    /// inserted concurrently by someone else under the same key is never
+    /// overwritten: a new ID is generated instead, and the entry is inserted
+    /// under it with the value already made. The "data maker" is called only
+    /// once, with the first key.
+    ///
+    /// This is cancellation safe. The entry is inserted by a single atomic
+    /// write, which is the last step and is never interrupted by dropping the
+    /// future or by the [`IdBuilder::timeout`]: either the returned entry was
+    /// inserted, or nothing was. If the future is dropped after that write
+    /// completed, though, the entry stays in the tree even if it is never
+    /// returned. Buffers are given back to the allocation in any case.
+    pub async fn generate<E, AK, AV>(self, db: &sled::Db) -> Result<(K, V), E>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+        A: buffer::Allocation,
+        TA: Clone,
+        FE: FnOnce(Error) -> E,
+        S: IdSource,
+        FK: FnMut(S::Id) -> AK,
+        AK: Future<Output = Result<K, E>>,
+        FV: FnOnce(&K) -> AV,
+        AV: Future<Output = Result<V, E>>,
+    {
+        let (mut ids, mut allocation, make_error, make_data) =
+            self.into_parts();
+        let tree = ids.tree.clone();
+        let mut guard = buffer::Guard::new(&mut allocation);
+        let (key_buf, val_buf) = guard.pair();
+
+        let output = tree.write_within(ids.timeout, async {
+            let id = ids.new_id(db, key_buf).await?;
+            let data = make_data(&id).await.map_err(TxError::Abort)?;
+            let hook = |_: &K, _: &V, _: &[TransactionalTree]| Ok(());
+            let id = ids
+                .insert_new(db, id, &data, &[], hook, key_buf, val_buf)
+                .await?;
+            Ok((id, data))
+        })
+        .await;
+
+        match output {
+            Ok(entry) => Ok(entry),
+            Err(TxError::Abort(error)) => Err(error),
+            Err(TxError::Storage(error)) => {
+                let error = tree.failed(error, "generate", None);
+                Err(make_error(error))
+            },
+        }
+    }
+
+    /// Generates candidate IDs until one is not a key of the tree.
+    async fn new_id<E, AK>(
+        &mut self,
+        db: &sled::Db,
+        key_buf: &mut Buffer,
+    ) -> Result<K, TxError<E>>
+    where
+        K: serde::Serialize,
+        S: IdSource,
+        FK: FnMut(S::Id) -> AK,
+        AK: Future<Output = Result<K, E>>,
+    {
+        loop {
+            let generated = task::block_in_place(|| self.source.generate(db))?;
+            let id = (self.make_id)(generated).await.map_err(TxError::Abort)?;
+            let contains = self
+                .tree
+                .contains_key_raw(&id, key_buf)
+                .await
+                .map_err(|error| error.at_key(key_buf.bytes()))?;
+            if !contains {
+                break Ok(id);
+            }
+            task::yield_now().await;
+        }
+    }
+
+    /// Inserts the entry under the given ID, in a transaction that also
+    /// includes the `extra` trees, see [`Tree::insert_new_in_raw`]. If the key
+    /// was inserted concurrently, generates a new ID and inserts the entry
+    /// under it instead. Returns the ID the entry was inserted under.
+    #[allow(clippy::too_many_arguments)]
+    async fn insert_new<E, AK, H>(
+        &mut self,
+        db: &sled::Db,
+        mut id: K,
+        data: &V,
+        extra: &[&sled::Tree],
+        mut hook: H,
+        key_buf: &mut Buffer,
+        val_buf: &mut Buffer,
+    ) -> Result<K, TxError<E>>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+        S: IdSource,
+        FK: FnMut(S::Id) -> AK,
+        AK: Future<Output = Result<K, E>>,
+        H: FnMut(&K, &V, &[TransactionalTree]) -> TxResult<(), E>,
+    {
+        loop {
+            let inserted = self
+                .tree
+                .insert_new_in_raw(
+                    &id,
+                    data,
+                    extra,
+                    &mut hook,
+                    key_buf,
+                    val_buf,
+                )
+                .await
+                .map_err(|error| {
+                    error.map_storage(|error| error.at_key(key_buf.bytes()))
+                })?;
+            if inserted {
+                break Ok(id);
+            }
+            task::yield_now().await;
+            id = self.new_id(db, key_buf).await?;
+        }
+    }
+
+    /// Generates the ID like [`IdBuilder::generate`], but inserts the entry
+    /// in a transaction that also includes the `trees`, e.g. indices or lists
+    /// of children, so the whole creation commits or aborts together. The
+    /// `trees` are a reference to a [`Tree`] or a tuple of them, see
+    /// [`TxTrees`], and must not include this tree. The `hook` receives the
+    /// new key and value, and typed views of `trees` in the same shape, see
+    /// [`TreeTx`], and may run more than once if there are concurrent writes.
+    /// Aborting the transaction in the hook gives back its error as it is. If
+    /// the generated key is inserted concurrently by someone else before the
+    /// transaction, the entry is inserted under a new ID instead, like
+    /// [`IdBuilder::generate`] does, running the hook again with the new key.
+    /// Cancellation safe like [`IdBuilder::generate`].
+    pub async fn generate_in<'trees, E, AK, AV, T, H>(
+        self,
         db: &sled::Db,
+        trees: T,
+        mut hook: H,
     ) -> Result<(K, V), E>
     where
+        K: serde::Serialize,
+        V: serde::Serialize,
         A: buffer::Allocation,
+        TA: Clone,
         FE: FnOnce(Error) -> E,
-        FK: FnMut(Id) -> AK,
+        S: IdSource,
+        FK: FnMut(S::Id) -> AK,
         AK: Future<Output = Result<K, E>>,
         FV: FnOnce(&K) -> AV,
         AV: Future<Output = Result<V, E>>,
+        T: TxTrees<'trees>,
+        H: FnMut(&K, &V, &T::View) -> TxResult<(), E>,
     {
-        let mut key_buf = self.allocation.make();
-        let mut val_buf = self.allocation.make();
+        let (mut ids, mut allocation, make_error, make_data) =
+            self.into_parts();
+        let tree = ids.tree.clone();
+        let mut guard = buffer::Guard::new(&mut allocation);
+        let (key_buf, val_buf) = guard.pair();
+        let mut storage = Vec::new();
+        trees.storage(&mut storage);
+        let hook = |key: &K, val: &V, mut txs: &[TransactionalTree]| {
+            hook(key, val, &trees.view(&mut txs))
+        };
 
-        let output = loop {
-            let generated = match task::block_in_place(|| db.generate_id()) {
-                Ok(id) => id,
-                Err(error) => break Err((self.make_error)(error.into())),
-            };
-            let id = match (self.make_id)(generated).await {
-                Ok(id) => id,
-                Err(error) => break Err(error),
-            };
+        let output = tree.write_within(ids.timeout, async {
+            let id = ids.new_id(db, key_buf).await?;
+            let data = make_data(&id).await.map_err(TxError::Abort)?;
+            let id = ids
+                .insert_new(db, id, &data, &storage, hook, key_buf, val_buf)
+                .await?;
+            Ok((id, data))
+        })
+        .await;
 
-            let contains =
-                match self.tree.contains_key_raw(&id, &mut key_buf).await {
-                    Ok(contains) => contains,
-                    Err(error) => break Err((self.make_error)(error.into())),
-                };
+        match output {
+            Ok(entry) => Ok(entry),
+            Err(TxError::Abort(error)) => Err(error),
+            Err(TxError::Storage(error)) => {
+                let error = tree.failed(error, "generate_in", None);
+                Err(make_error(error))
+            },
+        }
+    }
 
-            if !contains {
-                let data = match (self.make_data)(&id).await {
-                    Ok(data) => data,
-                    Err(error) => break Err(error),
-                };
-                if let Err(error) = self
-                    .tree
-                    .insert_raw(&id, &data, &mut key_buf, &mut val_buf)
-                    .await
-                {
-                    break Err((self.make_error)(error.into()));
+    async fn generate_many_raw<E, AK, I>(
+        &mut self,
+        db: &sled::Db,
+        values: I,
+        key_buf: &mut Buffer,
+        val_buf: &mut Buffer,
+    ) -> Result<Vec<(K, V)>, TxError<E>>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+        S: IdSource,
+        FK: FnMut(S::Id) -> AK,
+        AK: Future<Output = Result<K, E>>,
+        I: IntoIterator<Item = V>,
+    {
+        let values: Vec<V> = values.into_iter().collect();
+        let mut encoded_values = Vec::with_capacity(values.len());
+        let codec = &self.tree.shared().codec;
+        for val in &values {
+            encoded_values.push(encode_value(val_buf, val, codec)?.to_vec());
+        }
+
+        let mut slots: Vec<Option<(K, Vec<u8>)>> =
+            values.iter().map(|_| None).collect();
+        let mut taken = BTreeSet::new();
+        loop {
+            let pending: Vec<usize> = slots
+                .iter()
+                .enumerate()
+                .filter(|(_, slot)| slot.is_none())
+                .map(|(index, _)| index)
+                .collect();
+
+            if !pending.is_empty() {
+                let generated = task::block_in_place(|| {
+                    self.source.generate_block(db, pending.len())
+                })?;
+                for (index, generated) in pending.into_iter().zip(generated) {
+                    let id = (self.make_id)(generated)
+                        .await
+                        .map_err(TxError::Abort)?;
+                    let encoded_key = encode_key(key_buf, &id)?.to_vec();
+                    // Candidates repeated within the batch, or already found
+                    // in the tree, are generated again.
+                    if taken.insert(encoded_key.clone()) {
+                        slots[index] = Some((id, encoded_key));
+                    }
                 }
+                continue;
+            }
 
-                break Ok((id, data));
+            let entries: Vec<(&[u8], &[u8])> = slots
+                .iter()
+                .flatten()
+                .zip(&encoded_values)
+                .map(|((_, encoded_key), encoded_value)| {
+                    (encoded_key.as_slice(), encoded_value.as_slice())
+                })
+                .collect();
+            let present = task::block_in_place(|| {
+                self.tree.insert_new_encoded(&entries)
+            })?;
+            if present.is_empty() {
+                break;
+            }
+            for index in present {
+                slots[index] = None;
             }
 
             task::yield_now().await;
-        };
+        }
+
+        Ok(slots.into_iter().flatten().map(|(id, _)| id).zip(values).collect())
+    }
+
+    /// Generates one ID for each of the given values, like
+    /// [`IdBuilder::generate`], but inserting every entry in a single atomic
+    /// transaction: either all of them are inserted or none. Candidate IDs
+    /// are drawn from the source in a block, see
+    /// [`IdSource::generate_block`], checked for collisions in bulk,
+    /// and only the colliding ones are generated again. The "data maker" is
+    /// not used, and only the "id maker" is required. Returns the inserted
+    /// pairs in the order of the values. Cancellation safe like
+    /// [`IdBuilder::generate`].
+    pub async fn generate_many<E, AK, I>(
+        self,
+        db: &sled::Db,
+        values: I,
+    ) -> Result<Vec<(K, V)>, E>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+        A: buffer::Allocation,
+        TA: Clone,
+        FE: FnOnce(Error) -> E,
+        S: IdSource,
+        FK: FnMut(S::Id) -> AK,
+        AK: Future<Output = Result<K, E>>,
+        I: IntoIterator<Item = V>,
+    {
+        let (mut ids, mut allocation, make_error, _) = self.into_parts();
+        let tree = ids.tree.clone();
+        let mut guard = buffer::Guard::new(&mut allocation);
+        let (key_buf, val_buf) = guard.pair();
+
+        let output = tree
+            .write_within(
+                ids.timeout,
+                ids.generate_many_raw(db, values, key_buf, val_buf),
+            )
+            .await;
+
+        match output {
+            Ok(entries) => Ok(entries),
+            Err(TxError::Abort(error)) => Err(error),
+            Err(TxError::Storage(error)) => {
+                let error = tree.failed(error, "generate_many", None);
+                Err(make_error(error))
+            },
+        }
+    }
+}
+
+impl<'tree, T, V, A, FE, FK, FV, S, TA>
+    IdBuilder<'tree, TypedId<T>, V, A, FE, FK, FV, S, TA>
+where
+    S: IdSource<Id = Id>,
+{
+    /// Sets the "id maker" to tag the generated ID as a [`TypedId`].
+    #[allow(clippy::type_complexity)]
+    pub fn typed_id_maker<E>(
+        self,
+    ) -> IdBuilder<
+        'tree,
+        TypedId<T>,
+        V,
+        A,
+        FE,
+        impl FnMut(Id) -> Ready<Result<TypedId<T>, E>>,
+        FV,
+        S,
+        TA,
+    > {
+        self.id_maker(TypedId::new)
+    }
+}
+
+impl<'tree, K, V, A, FE, FK, FV, S, TA>
+    IdBuilder<'tree, K, V, A, FE, FK, FV, S, TA>
+where
+    K: From<String>,
+    S: IdSource<Id = Id>,
+{
+    /// Sets the "id maker" to encode the generated ID as a short, opaque
+    /// string with the encoder of the tree, see [`Tree::encode_id`]. Use
+    /// [`Tree::parse_encoded_key`] to map such strings back to keys.
+    #[allow(clippy::type_complexity)]
+    pub fn encoded_id_maker<E>(
+        self,
+    ) -> IdBuilder<
+        'tree,
+        K,
+        V,
+        A,
+        FE,
+        impl FnMut(Id) -> Ready<Result<K, E>>,
+        FV,
+        S,
+        TA,
+    > {
+        let encoder = self.tree.shared().id_encoder.clone();
+        self.id_maker(move |id| K::from(encoder.encode(id)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        CallOptions, Durability, OpenOptions, Tree, TreeRef, Versioned,
+    };
+    use crate::{buffer::SharedPool, error::ErrorKind};
+    use futures::stream::StreamExt;
+    use std::{cell::Cell, time::Duration};
+    use tokio::time;
+
+    #[test]
+    fn handles_are_send_sync_clone() {
+        fn assert_send_sync_clone<T: Send + Sync + Clone>() {}
+        type Local = std::rc::Rc<Cell<u8>>;
+        assert_send_sync_clone::<Tree<Local, Local>>();
+        assert_send_sync_clone::<Tree<Local, Local, SharedPool>>();
+        assert_send_sync_clone::<TreeRef<'static, Local, Local>>();
+        assert_send_sync_clone::<Versioned<Local, Local>>();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_ref_borrows_values() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Tree::<u64, String>::open(&db, "tree").await.unwrap();
+        tree.insert(&1, &"one".to_string()).await.unwrap();
+        assert!(tree.get_ref(&2).await.unwrap().is_none());
+
+        let guard = tree.get_ref(&1).await.unwrap().unwrap();
+        let value: &str = guard.value().unwrap();
+        assert_eq!(value, "one");
+        let bytes = guard.bytes().as_ptr_range();
+        assert!(bytes.contains(&value.as_ptr()));
+        assert_eq!(guard.to_value().unwrap(), "one");
+
+        let pairs =
+            Tree::<u64, (String, Vec<u8>)>::open(&db, "pairs").await.unwrap();
+        let pair = ("two".to_string(), vec![2, 200]);
+        pairs.insert(&2, &pair).await.unwrap();
+        let guard = pairs.get_ref(&2).await.unwrap().unwrap();
+        assert_eq!(guard.value().unwrap(), ("two", &[2, 200][..]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lookups_by_borrowed_keys() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let names = Tree::<String, u8>::open(&db, "names").await.unwrap();
+        names.insert(&"ann".to_string(), &1).await.unwrap();
+        assert_eq!(names.get("ann").await.unwrap(), Some(1));
+        assert!(names.contains_key("ann").await.unwrap());
+        assert!(!names.contains_key("bob").await.unwrap());
+        assert_eq!(names.remove("ann").await.unwrap(), Some(1));
+        assert_eq!(names.get("ann").await.unwrap(), None);
+
+        let paths = Tree::<Vec<u16>, u8>::open(&db, "paths").await.unwrap();
+        paths.insert(&vec![1, 300], &2).await.unwrap();
+        let path: &[u16] = &[1, 300];
+        assert_eq!(paths.get(path).await.unwrap(), Some(2));
+        assert!(paths.contains_key(path).await.unwrap());
+        assert!(!paths.contains_key(&[1][..]).await.unwrap());
+        assert_eq!(paths.remove(path).await.unwrap(), Some(2));
+        assert!(!paths.contains_key(path).await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn remove_range_in_batches() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let options = OpenOptions::new().expiry(true);
+        let tree = Tree::<u32, u32>::open_with(&db, "tree", &options)
+            .await
+            .unwrap();
+        for key in 0..2500 {
+            tree.insert(&key, &key).await.unwrap();
+        }
+        tree.insert_with_ttl(&2600, &0, Duration::from_millis(0))
+            .await
+            .unwrap();
+
+        assert_eq!(tree.remove_range(100..2200).await.unwrap(), 2100);
+        assert_eq!(tree.remove_range(2400..).await.unwrap(), 100);
+        assert_eq!(tree.remove_range(2400..).await.unwrap(), 0);
+        assert!(tree.contains_key(&99).await.unwrap());
+        assert!(!tree.contains_key(&100).await.unwrap());
+        assert!(!tree.contains_key(&2199).await.unwrap());
+        assert!(tree.contains_key(&2200).await.unwrap());
+        assert_eq!(tree.iter().count().await, 300);
+        let expired = crate::encode(2600u32).unwrap();
+        assert!(db.open_tree("tree").unwrap().get(expired).unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retain_skips_changed_entries() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Tree::<u32, u32>::open(&db, "tree").await.unwrap();
+        for key in 0..10 {
+            tree.insert(&key, &key).await.unwrap();
+        }
+        let storage = db.open_tree("tree").unwrap();
+        let changed = crate::encode(5u32).unwrap();
+        let removed = tree
+            .retain(|&key, _| {
+                if key == 5 {
+                    let value = crate::encode(50u32).unwrap();
+                    storage.insert(&changed, value).unwrap();
+                }
+                key % 2 == 0
+            })
+            .await
+            .unwrap();
+        assert_eq!(removed, 4);
+        assert_eq!(tree.get(&5).await.unwrap(), Some(50));
+        assert_eq!(tree.get(&3).await.unwrap(), None);
+        assert_eq!(tree.get(&4).await.unwrap(), Some(4));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn versions_are_not_reused() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let options = OpenOptions::new().envelope(true);
+        let tree = Tree::<u8, String>::open_with(&db, "tree", &options)
+            .await
+            .unwrap();
+        tree.insert(&0, &"a".into()).await.unwrap();
+        tree.insert(&0, &"b".into()).await.unwrap();
+        tree.remove(&0).await.unwrap();
+        let stale = tree.insert_if_version(&0, &"c".into(), Some(1)).await;
+        assert!(stale.unwrap().is_err());
+
+        let created = tree.insert_if_version(&0, &"c".into(), None).await;
+        let created = created.unwrap().unwrap();
+        assert!(created.version() > 2);
+        let stale = tree.insert_if_version(&0, &"d".into(), Some(1)).await;
+        assert_eq!(stale.unwrap().unwrap_err().current(), Some(created));
+
+        tree.remove(&0).await.unwrap();
+        tree.insert(&0, &"e".into()).await.unwrap();
+        let (_, metadata) = tree.get_meta(&0).await.unwrap().unwrap();
+        assert!(metadata.version() > created.version());
+
+        let reopened = Tree::<u8, String>::open_with(&db, "tree", &options)
+            .await
+            .unwrap();
+        reopened.remove(&0).await.unwrap();
+        reopened.insert(&0, &"f".into()).await.unwrap();
+        let (_, last) = reopened.get_meta(&0).await.unwrap().unwrap();
+        assert!(last.version() > metadata.version());
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn corrupted_envelope_is_not_trusted() {
+        use crate::codec::Checksum;
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let options =
+            OpenOptions::new().envelope(true).checksum(Checksum::Crc32c);
+        let tree = Tree::<u8, String>::open_with(&db, "tree", &options)
+            .await
+            .unwrap();
+        let metadata = tree.insert_if_version(&0, &"a".into(), None).await;
+        let version = metadata.unwrap().unwrap().version();
+        let storage = db.open_tree("tree").unwrap();
+        let (key, stored) = storage.iter().next().unwrap().unwrap();
+        let mut forged = stored.to_vec();
+        forged[16..24].copy_from_slice(&7u64.to_be_bytes());
+        storage.insert(&key, forged).unwrap();
+
+        let value = "b".to_string();
+        let error = tree.insert_if_version(&0, &value, Some(7)).await;
+        assert!(error.unwrap_err().is_corruption());
+        let error = tree.insert_if_version(&0, &value, Some(version)).await;
+        assert!(error.unwrap_err().is_corruption());
+        assert!(tree.insert(&0, &value).await.unwrap_err().is_corruption());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn generate_does_not_overwrite() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Tree::<u64, String>::open(&db, "tree").await.unwrap();
+        let taken = Cell::new(None);
+        let ids = tree.id_builder().id_maker(|id| id);
+        let ids = ids.async_data_maker(|&id| {
+            let tree = tree.clone();
+            taken.set(Some(id));
+            async move {
+                tree.insert(&id, &"taken".to_string()).await.unwrap();
+                format!("made for {}", id)
+            }
+        });
+
+        let (id, data) = ids.generate(&db).await.unwrap();
+        let taken = taken.get().unwrap();
+        assert_ne!(id, taken);
+        assert_eq!(data, format!("made for {}", taken));
+        assert_eq!(tree.get(&id).await.unwrap(), Some(data));
+        assert_eq!(tree.get(&taken).await.unwrap(), Some("taken".into()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn generate_many_retries_collisions() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let options = OpenOptions::new().sequence(10);
+        let tree = Tree::<u64, String>::open_with(&db, "tree", &options)
+            .await
+            .unwrap();
+        tree.insert(&1, &"taken".to_string()).await.unwrap();
+
+        let source = tree.sequence_ids().unwrap();
+        let ids = tree.id_builder().id_source(source).id_maker(|id| id);
+        let values = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let entries = ids.generate_many(&db, values).await.unwrap();
+        let expected = vec![
+            (0, "a".to_string()),
+            (3, "b".to_string()),
+            (2, "c".to_string()),
+        ];
+        assert_eq!(entries, expected);
+        assert_eq!(tree.get(&1).await.unwrap(), Some("taken".into()));
+        assert_eq!(tree.get(&3).await.unwrap(), Some("b".into()));
+
+        // Candidates repeated within the batch are generated again too.
+        let source = tree.sequence_ids().unwrap();
+        let ids = tree.id_builder().id_source(source);
+        let ids = ids.id_maker(|id| 100 + id / 2);
+        let values = vec!["d".to_string(), "e".to_string()];
+        let entries: Vec<_> = ids.generate_many(&db, values).await.unwrap();
+        assert_eq!(entries[0], (102, "d".to_string()));
+        assert_eq!(entries[1], (103, "e".to_string()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn generate_values_that_only_serialize() {
+        struct Written(u8);
+
+        impl serde::Serialize for Written {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_u8(self.0)
+            }
+        }
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Tree::<u64, Written>::open(&db, "tree").await.unwrap();
+        let ids = tree.id_builder().id_maker(|id| id);
+        let (id, _) =
+            ids.data_maker(|_| Written(1)).generate(&db).await.unwrap();
+        let ids = tree.id_builder().id_maker(|id| id);
+        let entries = ids.generate_many(&db, vec![Written(2)]).await.unwrap();
+
+        let raw = Tree::<u64, u8>::open(&db, "tree").await.unwrap();
+        assert_eq!(raw.get(&id).await.unwrap(), Some(1));
+        assert_eq!(raw.get(&entries[0].0).await.unwrap(), Some(2));
+    }
 
-        self.allocation.save(key_buf);
-        self.allocation.save(val_buf);
+    #[tokio::test(flavor = "multi_thread")]
+    async fn borrowed_options() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Tree::<u64, String>::open(&db, "tree").await.unwrap();
+        let options = CallOptions::new()
+            .allocation(SharedPool::default())
+            .durability(Durability::Flushed);
+        let view = tree.with_options(options);
+        view.insert(&1, &"one".to_string()).await.unwrap();
+        assert_eq!(tree.get(&1).await.unwrap(), Some("one".into()));
+        assert_eq!(view.iter().skip_invalid(drop).count().await, 1);
 
-        output
+        let options = tree.options().clone().timeout(Duration::from_millis(10));
+        let view = tree.with_options(options);
+        let ids = view.id_builder();
+        let ids = ids.async_id_maker(|id| async move {
+            time::sleep(Duration::from_millis(100)).await;
+            id
+        });
+        let error = ids.data_maker(|_| String::new()).generate(&db).await;
+        let error = error.unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::Timeout(_)), "{}", error);
+        assert_eq!(tree.iter().skip_invalid(drop).count().await, 1);
     }
 }
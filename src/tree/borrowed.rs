@@ -0,0 +1,138 @@
+//! Values borrowed from storage, decoded without copying.
+
+use crate::{
+    decode_limited,
+    error::{Error, Stage},
+};
+use sled::IVec;
+use std::{fmt, marker::PhantomData};
+
+/// A value type with a borrowed counterpart of the same encoding, which
+/// [`Ref::value`] decodes into. Implement it for your own types by pairing
+/// them with a variant that has borrowed fields, such as `&str` in place of
+/// `String`.
+pub trait Borrowable {
+    /// The type decoded from the bytes of a value, borrowing from them.
+    type Ref<'a>: serde::Deserialize<'a>;
+}
+
+/// Implements [`Borrowable`] for types which borrow nothing.
+macro_rules! borrow_as_self {
+    ($($ty:ty),*) => {
+        $(
+            impl Borrowable for $ty {
+                type Ref<'a> = Self;
+            }
+        )*
+    };
+}
+
+borrow_as_self!(
+    (), bool, char, u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64
+);
+
+impl Borrowable for String {
+    type Ref<'a> = &'a str;
+}
+
+impl Borrowable for Box<str> {
+    type Ref<'a> = &'a str;
+}
+
+impl Borrowable for Vec<u8> {
+    type Ref<'a> = &'a [u8];
+}
+
+impl Borrowable for Box<[u8]> {
+    type Ref<'a> = &'a [u8];
+}
+
+impl<T> Borrowable for Option<T>
+where
+    T: Borrowable,
+{
+    type Ref<'a> = Option<T::Ref<'a>>;
+}
+
+impl<T, U> Borrowable for (T, U)
+where
+    T: Borrowable,
+    U: Borrowable,
+{
+    type Ref<'a> = (T::Ref<'a>, U::Ref<'a>);
+}
+
+/// A guard over the encoded bytes of a value, as returned by
+/// [`Tree::get_ref`](super::Tree::get_ref).
+///
+/// The bytes are shared with sled when possible, so decoding them into the
+/// [`Borrowable::Ref`] of `V`, such as `&str` for `String`, does not allocate.
+/// Values of compressed or encrypted trees are decoded into a single buffer
+/// first.
+pub struct Ref<V> {
+    bytes: IVec,
+    limit: u64,
+    storage: sled::Tree,
+    key: IVec,
+    _marker: PhantomData<fn() -> V>,
+}
+
+impl<V> Ref<V> {
+    pub(crate) fn new(
+        bytes: IVec,
+        limit: u64,
+        storage: &sled::Tree,
+        key: &[u8],
+    ) -> Self {
+        Self {
+            bytes,
+            limit,
+            storage: storage.clone(),
+            key: IVec::from(key),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Decodes the value into the borrowed counterpart of `V`, borrowing from
+    /// this guard. Decodes again at every call.
+    pub fn value(&self) -> Result<V::Ref<'_>, Error>
+    where
+        V: Borrowable,
+    {
+        self.decode()
+    }
+
+    /// Decodes the value into an owned `V`.
+    pub fn to_value(&self) -> Result<V, Error>
+    where
+        for<'de> V: serde::Deserialize<'de>,
+    {
+        self.decode()
+    }
+
+    fn decode<'a, T>(&'a self) -> Result<T, Error>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        decode_limited(&self.bytes, self.limit).map_err(|error| {
+            error
+                .in_stage(Stage::ValueDecode)
+                .at(&self.storage, &self.key)
+                .in_operation("get_ref", &self.storage)
+        })
+    }
+
+    /// Returns the encoded bytes of the value.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl<V> fmt::Debug for Ref<V> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Ref")
+            .field("bytes", &self.bytes)
+            .field("key", &self.key)
+            .finish()
+    }
+}
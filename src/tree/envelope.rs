@@ -0,0 +1,216 @@
+//! Value envelopes, holding metadata about an entry along with its value.
+//!
+//! An envelope is a fixed size header followed by the stored value, which the
+//! codec transforms, authenticating the header when encrypting. The header
+//! holds the creation time, the last update time (both as milliseconds since
+//! the UNIX epoch) and the version, all encoded as big endian `u64`.
+//!
+//! The latest version of each key is also kept in a companion tree, as a big
+//! endian `u64`, so that versions keep increasing after an entry is removed.
+
+use super::companion_name;
+use crate::error::Error;
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+use std::{
+    convert::TryInto,
+    error::Error as ErrorTrait,
+    fmt, io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Size of the header of an envelope.
+pub(crate) const HEADER_SIZE: usize = 24;
+
+/// Metadata about an entry of a tree opened with envelopes. See
+/// [`OpenOptions::envelope`](super::OpenOptions::envelope).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Metadata {
+    created_at: u64,
+    updated_at: u64,
+    version: u64,
+}
+
+impl Metadata {
+    /// Returns when the entry was created.
+    pub fn created_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.created_at)
+    }
+
+    /// Returns when the entry was last updated.
+    pub fn updated_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.updated_at)
+    }
+
+    /// Returns the version of the entry. It starts at `1` when the entry is
+    /// first created, and increases on every update. Versions are never
+    /// reused for a key, even after the entry is removed and created again.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Creates metadata from its fields, given as milliseconds since the
+    /// UNIX epoch.
+    pub(crate) fn new(created_at: u64, updated_at: u64, version: u64) -> Self {
+        Self { created_at, updated_at, version }
+    }
+
+    /// Returns when the entry was created, in milliseconds since the UNIX
+    /// epoch.
+    pub(crate) fn created_at_millis(&self) -> u64 {
+        self.created_at
+    }
+
+    /// Returns when the entry was last updated, in milliseconds since the UNIX
+    /// epoch.
+    pub(crate) fn updated_at_millis(&self) -> u64 {
+        self.updated_at
+    }
+
+    /// Returns the metadata of an entry written at `now`, given its previous
+    /// metadata, or `None` if the entry is new, and the latest version of its
+    /// key.
+    pub(crate) fn next(previous: Option<Self>, latest: u64, now: u64) -> Self {
+        match previous {
+            Some(previous) => Self {
+                created_at: previous.created_at,
+                updated_at: now,
+                version: previous.version.max(latest).saturating_add(1),
+            },
+            None => Self {
+                created_at: now,
+                updated_at: now,
+                version: latest.saturating_add(1),
+            },
+        }
+    }
+}
+
+/// Error returned when a conditional insertion found an entry version other
+/// than the expected. See [`Tree::insert_if_version`](super::Tree).
+/// Converts into an [`Error`] of kind
+/// [`ErrorKind::Conflict`](crate::error::ErrorKind::Conflict).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMismatch {
+    current: Option<Metadata>,
+}
+
+impl VersionMismatch {
+    pub(crate) fn new(current: Option<Metadata>) -> Self {
+        Self { current }
+    }
+
+    /// Returns the metadata of the current entry, or `None` if there is no
+    /// entry.
+    pub fn current(&self) -> Option<Metadata> {
+        self.current
+    }
+}
+
+impl fmt::Display for VersionMismatch {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.current {
+            Some(current) => {
+                write!(fmt, "version mismatch, current is {}", current.version)
+            },
+            None => write!(fmt, "version mismatch, entry does not exist"),
+        }
+    }
+}
+
+impl ErrorTrait for VersionMismatch {}
+
+impl From<VersionMismatch> for Error {
+    fn from(error: VersionMismatch) -> Self {
+        Error::conflict(error)
+    }
+}
+
+/// Splits an envelope into its metadata and stored value.
+pub(crate) fn open(bytes: &[u8]) -> Result<(Metadata, &[u8]), Error> {
+    if bytes.len() < HEADER_SIZE {
+        let error = io::Error::from(io::ErrorKind::UnexpectedEof);
+        return Err(bincode::Error::from(error).into());
+    }
+    let (header, value) = bytes.split_at(HEADER_SIZE);
+    let metadata = Metadata {
+        created_at: field(header, 0),
+        updated_at: field(header, 1),
+        version: field(header, 2),
+    };
+    Ok((metadata, value))
+}
+
+/// Reads the field with the given index of a header.
+fn field(header: &[u8], index: usize) -> u64 {
+    let start = index * 8;
+    u64::from_be_bytes(header[start..start + 8].try_into().unwrap())
+}
+
+/// Makes the header of an envelope with the given metadata.
+pub(crate) fn header(metadata: Metadata) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[..8].copy_from_slice(&metadata.created_at.to_be_bytes());
+    header[8..16].copy_from_slice(&metadata.updated_at.to_be_bytes());
+    header[16..].copy_from_slice(&metadata.version.to_be_bytes());
+    header
+}
+
+/// Opens the companion tree of the latest versions of the keys of the tree
+/// with the given name. Blocking.
+pub(crate) fn open_latest(
+    db: &sled::Db,
+    name: &[u8],
+) -> Result<sled::Tree, Error> {
+    Ok(db.open_tree(companion_name(name, "latest"))?)
+}
+
+/// View of the latest versions of the keys of a tree inside of a transaction.
+#[derive(Clone, Copy)]
+pub(crate) struct LatestTx<'tx> {
+    latest: &'tx TransactionalTree,
+}
+
+impl<'tx> LatestTx<'tx> {
+    /// Creates the view from the transactional version of the tree returned
+    /// by [`open_latest`].
+    pub(crate) fn new(latest: &'tx TransactionalTree) -> Self {
+        Self { latest }
+    }
+
+    /// Returns the latest version of the entry with the given key, or `0` if
+    /// it was never written.
+    pub(crate) fn get(
+        &self,
+        key: &[u8],
+    ) -> Result<u64, UnabortableTransactionError> {
+        let version = self.latest.get(key)?.and_then(|bytes| {
+            bytes.as_ref().try_into().ok().map(u64::from_be_bytes)
+        });
+        Ok(version.unwrap_or(0))
+    }
+
+    /// Records that the entry with the given key is put with the given stored
+    /// value, whose envelope holds the new version.
+    pub(crate) fn put(
+        &self,
+        key: &[u8],
+        stored: &[u8],
+    ) -> Result<(), UnabortableTransactionError> {
+        if stored.len() >= HEADER_SIZE {
+            self.latest.insert(key, &field(stored, 2).to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Records that the entry with the given key, whose latest version was
+    /// `latest`, is removed. The removal takes the next version, so that it
+    /// is not reused.
+    pub(crate) fn remove(
+        &self,
+        key: &[u8],
+        latest: u64,
+    ) -> Result<(), UnabortableTransactionError> {
+        self.latest.insert(key, &latest.saturating_add(1).to_be_bytes())?;
+        Ok(())
+    }
+}
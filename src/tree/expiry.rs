@@ -0,0 +1,164 @@
+//! Time-to-live expiry of tree entries, tracked in companion trees.
+//!
+//! Deadlines are stored as milliseconds since the UNIX epoch, encoded as big
+//! endian `u64`, in two companion trees: one maps encoded keys to their
+//! deadlines, and the other is an index ordered by deadline, whose keys are the
+//! deadline followed by the encoded key.
+
+use super::{companion_name, TxResult};
+use crate::error::Error;
+use sled::transaction::{
+    Transactional, TransactionalTree, UnabortableTransactionError,
+};
+use std::{
+    convert::TryInto,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Size of an encoded deadline.
+const DEADLINE_SIZE: usize = 8;
+
+/// Returns the given time as milliseconds since the UNIX epoch.
+pub(crate) fn millis(time: SystemTime) -> u64 {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    elapsed.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Returns the current time as milliseconds since the UNIX epoch.
+pub(crate) fn now() -> u64 {
+    millis(SystemTime::now())
+}
+
+/// Returns the deadline for an entry living for the given time-to-live.
+pub(crate) fn deadline_after(ttl: Duration) -> u64 {
+    let ttl = ttl.as_millis().try_into().unwrap_or(u64::MAX);
+    now().saturating_add(ttl)
+}
+
+fn decode_deadline(bytes: &[u8]) -> u64 {
+    let mut encoded = [0; DEADLINE_SIZE];
+    let len = bytes.len().min(DEADLINE_SIZE);
+    encoded[..len].copy_from_slice(&bytes[..len]);
+    u64::from_be_bytes(encoded)
+}
+
+fn index_key(deadline: u64, key: &[u8]) -> Vec<u8> {
+    let mut index_key = Vec::with_capacity(DEADLINE_SIZE + key.len());
+    index_key.extend_from_slice(&deadline.to_be_bytes());
+    index_key.extend_from_slice(key);
+    index_key
+}
+
+/// Companion trees tracking the deadlines of entries of a tree.
+#[derive(Debug, Clone)]
+pub(crate) struct Expiry {
+    deadlines: sled::Tree,
+    index: sled::Tree,
+}
+
+impl Expiry {
+    /// Opens the companion trees of the tree with the given name. Blocking.
+    pub(crate) fn open(db: &sled::Db, name: &[u8]) -> Result<Self, Error> {
+        let deadlines = db.open_tree(companion_name(name, "deadlines"))?;
+        let index = db.open_tree(companion_name(name, "expiry"))?;
+        Ok(Self { deadlines, index })
+    }
+
+    /// Returns the companion trees, in the order expected by
+    /// [`ExpiryTx::new`].
+    pub(crate) fn trees(&self) -> [&sled::Tree; 2] {
+        [&self.deadlines, &self.index]
+    }
+
+    /// Tests whether the entry with the given key has expired at `now`.
+    /// Blocking.
+    pub(crate) fn is_expired(
+        &self,
+        key: &[u8],
+        now: u64,
+    ) -> Result<bool, Error> {
+        match self.deadlines.get(key)? {
+            Some(deadline) => Ok(decode_deadline(&deadline) <= now),
+            None => Ok(false),
+        }
+    }
+
+    /// Removes every entry of `storage` that has expired, returning how many
+    /// entries were removed. Blocking.
+    pub(crate) fn sweep(&self, storage: &sled::Tree) -> Result<usize, Error> {
+        let end = now().saturating_add(1).to_be_bytes();
+        let mut removed = 0;
+
+        for entry in self.index.range(..end) {
+            let (index_key, _) = entry?;
+            let (deadline, key) = index_key.split_at(DEADLINE_SIZE);
+            let expired = (storage, &self.deadlines, &self.index).transaction(
+                |(storage, deadlines, index)| -> TxResult<_> {
+                    index.remove(&index_key)?;
+                    let current = deadlines.get(key)?;
+                    let expired = current.as_deref() == Some(deadline);
+                    if expired {
+                        deadlines.remove(key)?;
+                        storage.remove(key)?;
+                    }
+                    Ok(expired)
+                },
+            )?;
+            if expired {
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+/// View of the companion trees inside of a transaction.
+#[derive(Clone, Copy)]
+pub(crate) struct ExpiryTx<'tx> {
+    deadlines: &'tx TransactionalTree,
+    index: &'tx TransactionalTree,
+}
+
+impl<'tx> ExpiryTx<'tx> {
+    /// Creates the view from the transactional versions of the trees returned
+    /// by [`Expiry::trees`].
+    pub(crate) fn new(trees: &'tx [TransactionalTree]) -> Self {
+        Self { deadlines: &trees[0], index: &trees[1] }
+    }
+
+    /// Tests whether the entry with the given key has expired at `now`.
+    pub(crate) fn is_expired(
+        &self,
+        key: &[u8],
+        now: u64,
+    ) -> Result<bool, UnabortableTransactionError> {
+        match self.deadlines.get(key)? {
+            Some(deadline) => Ok(decode_deadline(&deadline) <= now),
+            None => Ok(false),
+        }
+    }
+
+    /// Sets the deadline of the entry with the given key, or removes it if
+    /// `None`.
+    pub(crate) fn set_deadline(
+        &self,
+        key: &[u8],
+        deadline: Option<u64>,
+    ) -> Result<(), UnabortableTransactionError> {
+        let old_deadline = match deadline {
+            Some(deadline) => {
+                self.index.insert(index_key(deadline, key), &[])?;
+                self.deadlines.insert(key, &deadline.to_be_bytes())?
+            },
+            None => self.deadlines.remove(key)?,
+        };
+        if let Some(old_deadline) = old_deadline {
+            let old_deadline = decode_deadline(&old_deadline);
+            if Some(old_deadline) != deadline {
+                self.index.remove(index_key(old_deadline, key))?;
+            }
+        }
+        Ok(())
+    }
+}
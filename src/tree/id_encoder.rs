@@ -0,0 +1,220 @@
+//! Reversible encoding of IDs as short, opaque strings.
+
+use super::Id;
+use crate::error::{Error, InvalidId};
+
+/// Default alphabet of the encoder.
+const DEFAULT_ALPHABET: &str =
+    "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+/// Encodes [`Id`]s as short, opaque strings, e.g. for URLs, and decodes them
+/// back, in the style of Sqids/Hashids. The first character selects a
+/// rotation of the alphabet and the others are the digits of the ID in the
+/// rotated alphabet, so consecutive IDs produce unrelated strings.
+///
+/// The alphabet is shuffled with the salt, so both must stay the same for the
+/// whole lifetime of the tree, or previously handed out strings no longer
+/// decode to the same IDs. This is an obfuscation, not encryption: it hides
+/// the ordering and the count of IDs from casual observers only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdEncoder {
+    alphabet: Vec<u8>,
+}
+
+impl IdEncoder {
+    /// Creates an encoder with the given alphabet, shuffled with the given
+    /// salt. An empty salt leaves the order of the alphabet to the encoder
+    /// alone.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the alphabet is not ASCII, repeats a character, or has fewer
+    /// than 3 characters.
+    pub fn new(alphabet: &str, salt: &str) -> Self {
+        assert!(alphabet.is_ascii(), "ID alphabet must be ASCII");
+        assert!(alphabet.len() >= 3, "ID alphabet must have 3+ characters");
+        let mut alphabet = alphabet.as_bytes().to_vec();
+        for (i, &ch) in alphabet.iter().enumerate() {
+            assert!(
+                !alphabet[..i].contains(&ch),
+                "ID alphabet must not repeat characters"
+            );
+        }
+        salt_shuffle(&mut alphabet, salt.as_bytes());
+        shuffle(&mut alphabet);
+        Self { alphabet }
+    }
+
+    /// Encodes the given ID.
+    pub fn encode(&self, id: Id) -> String {
+        let (prefix, digits) = self.digits(self.offset(id));
+        let base = digits.len() as u64;
+        let mut output = Vec::new();
+        let mut rest = id;
+        loop {
+            output.push(digits[(rest % base) as usize]);
+            rest /= base;
+            if rest == 0 {
+                break;
+            }
+        }
+        output.push(prefix);
+        output.reverse();
+        output.into_iter().map(char::from).collect()
+    }
+
+    /// Decodes the given string back into the ID it was encoded from.
+    /// Produces [`ErrorKind::InvalidId`](crate::error::ErrorKind) if the
+    /// string was not produced by this encoder.
+    pub fn decode(&self, input: &str) -> Result<Id, Error> {
+        let invalid = || InvalidId::new(input);
+        let (&prefix, rest) =
+            input.as_bytes().split_first().ok_or_else(invalid)?;
+        if rest.is_empty() {
+            return Err(invalid().into());
+        }
+        let offset = self.position(prefix).ok_or_else(invalid)?;
+        let (_, digits) = self.digits(offset);
+        let base = digits.len() as u64;
+        let mut id: Id = 0;
+        for ch in rest {
+            let digit =
+                digits.iter().position(|d| d == ch).ok_or_else(invalid)?;
+            id = id
+                .checked_mul(base)
+                .and_then(|id| id.checked_add(digit as u64))
+                .ok_or_else(invalid)?;
+        }
+        // Leading zero digits and foreign prefixes decode to some ID as well,
+        // so only the string the ID encodes to is accepted.
+        if self.encode(id) != input {
+            return Err(invalid().into());
+        }
+        Ok(id)
+    }
+
+    /// Computes the rotation of the alphabet used for the given ID.
+    fn offset(&self, id: Id) -> usize {
+        let len = self.alphabet.len();
+        let index = (id % len as u64) as usize;
+        (usize::from(self.alphabet[index]) + index) % len
+    }
+
+    fn position(&self, ch: u8) -> Option<usize> {
+        self.alphabet.iter().position(|&other| other == ch)
+    }
+
+    /// Returns the prefix and the digits for the given rotation of the
+    /// alphabet.
+    fn digits(&self, offset: usize) -> (u8, Vec<u8>) {
+        let mut rotated = self.alphabet.clone();
+        rotated.rotate_left(offset);
+        let prefix = rotated[0];
+        rotated.reverse();
+        rotated.remove(0);
+        (prefix, rotated)
+    }
+}
+
+impl Default for IdEncoder {
+    /// Creates an encoder with lowercase and uppercase ASCII letters and
+    /// digits as the alphabet, and no salt.
+    fn default() -> Self {
+        Self::new(DEFAULT_ALPHABET, "")
+    }
+}
+
+/// Shuffles the alphabet with the salt, as Hashids does.
+fn salt_shuffle(alphabet: &mut [u8], salt: &[u8]) {
+    if salt.is_empty() {
+        return;
+    }
+    let mut index = 0;
+    let mut sum = 0;
+    for i in (1..alphabet.len()).rev() {
+        index %= salt.len();
+        let value = usize::from(salt[index]);
+        sum += value;
+        let j = (value + index + sum) % i;
+        alphabet.swap(i, j);
+        index += 1;
+    }
+}
+
+/// Shuffles the alphabet deterministically, as Sqids does.
+fn shuffle(alphabet: &mut [u8]) {
+    let len = alphabet.len();
+    let (mut i, mut j) = (0, len - 1);
+    while j > 0 {
+        let r =
+            (i * j + usize::from(alphabet[i]) + usize::from(alphabet[j])) % len;
+        alphabet.swap(i, r);
+        i += 1;
+        j -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IdEncoder, DEFAULT_ALPHABET};
+    use crate::tree::Id;
+    use std::collections::HashSet;
+
+    fn ids() -> Vec<Id> {
+        let mut ids: Vec<Id> = (0..5000).collect();
+        let mut id: Id = 1;
+        while let Some(next) = id.checked_mul(3) {
+            ids.extend_from_slice(&[id - 1, id, id + 1]);
+            id = next;
+        }
+        let mut state: Id = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..5000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            ids.push(state);
+        }
+        ids.push(Id::MAX);
+        ids
+    }
+
+    fn encoders() -> Vec<IdEncoder> {
+        let mut encoders = Vec::new();
+        for alphabet in [DEFAULT_ALPHABET, "abc", "0123456789abcdef"] {
+            for salt in ["", "a", "salt", "a much longer salt than usual"] {
+                encoders.push(IdEncoder::new(alphabet, salt));
+            }
+        }
+        encoders
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        for encoder in encoders() {
+            let mut encoded = HashSet::new();
+            for id in ids() {
+                let string = encoder.encode(id);
+                assert_eq!(encoder.decode(&string).unwrap(), id, "{}", string);
+                encoded.insert((id, string));
+            }
+            let strings: HashSet<_> =
+                encoded.iter().map(|(_, string)| string).collect();
+            assert_eq!(strings.len(), encoded.len());
+        }
+    }
+
+    #[test]
+    fn decode_rejects_foreign_strings() {
+        let encoder = IdEncoder::default();
+        for input in ["", "a", "!!", "aé"] {
+            assert!(encoder.decode(input).is_err(), "{}", input);
+        }
+        let salted = IdEncoder::new(DEFAULT_ALPHABET, "salt");
+        for id in 0..1000 {
+            let string = encoder.encode(id);
+            if let Ok(decoded) = salted.decode(&string) {
+                assert_eq!(salted.encode(decoded), string);
+            }
+        }
+    }
+}
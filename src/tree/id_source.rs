@@ -0,0 +1,341 @@
+//! Sources of IDs for [`IdBuilder`](super::IdBuilder).
+
+use super::{sequence::Sequence, Id};
+use crate::error::Error;
+#[cfg(feature = "snowflake")]
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A source of candidate IDs, given to the "id maker" of an
+/// [`IdBuilder`](super::IdBuilder). Candidates which are already keys of the
+/// tree are discarded and another one is generated.
+pub trait IdSource {
+    /// The type of the generated IDs.
+    type Id;
+
+    /// Generates a new candidate ID. Blocking.
+    fn generate(&mut self, db: &sled::Db) -> Result<Self::Id, Error>;
+
+    /// Generates `count` new candidate IDs at once, as
+    /// [`IdBuilder::generate_many`](super::IdBuilder::generate_many) does.
+    /// Blocking. By default, calls [`IdSource::generate`] `count` times;
+    /// sources which can reserve many IDs in a single step override it.
+    fn generate_block(
+        &mut self,
+        db: &sled::Db,
+        count: usize,
+    ) -> Result<Vec<Self::Id>, Error> {
+        (0..count).map(|_| self.generate(db)).collect()
+    }
+}
+
+/// IDs generated by the database, see [`sled::Db::generate_id`]. These are
+/// unique in the whole database, but jump by large gaps after restarts. This
+/// is the default source.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DbIds;
+
+impl IdSource for DbIds {
+    type Id = Id;
+
+    fn generate(&mut self, db: &sled::Db) -> Result<Self::Id, Error> {
+        Ok(db.generate_id()?)
+    }
+
+    fn generate_block(
+        &mut self,
+        db: &sled::Db,
+        count: usize,
+    ) -> Result<Vec<Self::Id>, Error> {
+        // The database persists its counter only once per large interval of
+        // IDs, so drawing them one by one is a mere atomic increment each.
+        let mut ids = Vec::with_capacity(count);
+        for _ in 0..count {
+            ids.push(db.generate_id()?);
+        }
+        Ok(ids)
+    }
+}
+
+/// Numbers of the sequence of a tree, see
+/// [`Tree::sequence_ids`](super::Tree::sequence_ids). These are dense per
+/// tree, with gaps only as described by
+/// [`OpenOptions::sequence`](super::OpenOptions::sequence).
+#[derive(Debug, Clone)]
+pub struct SequenceIds {
+    sequence: Sequence,
+}
+
+impl SequenceIds {
+    pub(crate) fn new(sequence: Sequence) -> Self {
+        Self { sequence }
+    }
+}
+
+impl IdSource for SequenceIds {
+    type Id = Id;
+
+    fn generate(&mut self, _db: &sled::Db) -> Result<Self::Id, Error> {
+        self.sequence.next()
+    }
+
+    fn generate_block(
+        &mut self,
+        _db: &sled::Db,
+        count: usize,
+    ) -> Result<Vec<Self::Id>, Error> {
+        self.sequence.next_many(count)
+    }
+}
+
+/// Random (version 4) UUIDs.
+#[cfg(feature = "uuid")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidV4;
+
+#[cfg(feature = "uuid")]
+impl IdSource for UuidV4 {
+    type Id = uuid::Uuid;
+
+    fn generate(&mut self, _db: &sled::Db) -> Result<Self::Id, Error> {
+        Ok(uuid::Uuid::new_v4())
+    }
+}
+
+/// Time-ordered (version 7) UUIDs. Their encoding follows their creation
+/// time, so they make good keys for range queries.
+#[cfg(feature = "uuid")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidV7;
+
+#[cfg(feature = "uuid")]
+impl IdSource for UuidV7 {
+    type Id = uuid::Uuid;
+
+    fn generate(&mut self, _db: &sled::Db) -> Result<Self::Id, Error> {
+        Ok(uuid::Uuid::now_v7())
+    }
+}
+
+/// ULIDs, time-ordered IDs with a random part.
+#[cfg(feature = "ulid")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ulids;
+
+#[cfg(feature = "ulid")]
+impl IdSource for Ulids {
+    type Id = ulid::Ulid;
+
+    fn generate(&mut self, _db: &sled::Db) -> Result<Self::Id, Error> {
+        Ok(ulid::Ulid::new())
+    }
+}
+
+/// Number of bits of the node ID of a snowflake.
+#[cfg(feature = "snowflake")]
+const NODE_BITS: u32 = 10;
+
+/// Number of bits of the sequence number of a snowflake.
+#[cfg(feature = "snowflake")]
+const SEQUENCE_BITS: u32 = 12;
+
+/// Default epoch of snowflakes: 2020-01-01T00:00:00Z.
+#[cfg(feature = "snowflake")]
+const DEFAULT_EPOCH: Duration = Duration::from_millis(1_577_836_800_000);
+
+#[cfg(feature = "snowflake")]
+#[derive(Debug, Default)]
+struct SnowflakeClock {
+    millis: u64,
+    sequence: u64,
+}
+
+/// Snowflake-style IDs: milliseconds since an epoch in the upper 41 bits, then
+/// a 10-bit node ID, then a 12-bit sequence number. IDs of different nodes
+/// never collide, and IDs of one node are strictly increasing, even if the
+/// system clock goes backwards or more than 4096 IDs are generated in a
+/// millisecond, in which case the timestamp runs ahead of the clock.
+///
+/// Clones share their state, so a single source should be created per node
+/// and cloned into each builder.
+#[cfg(feature = "snowflake")]
+#[derive(Debug, Clone)]
+pub struct Snowflake {
+    node: u64,
+    epoch: Duration,
+    clock: Arc<Mutex<SnowflakeClock>>,
+}
+
+#[cfg(feature = "snowflake")]
+impl Snowflake {
+    /// Creates a source for the given node ID, with the default epoch of
+    /// 2020-01-01T00:00:00Z.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `node` does not fit in 10 bits, i.e. it is 1024 or greater.
+    pub fn new(node: u16) -> Self {
+        assert!(
+            u32::from(node) < 1 << NODE_BITS,
+            "snowflake node out of range"
+        );
+        Self {
+            node: u64::from(node),
+            epoch: DEFAULT_EPOCH,
+            clock: Arc::new(Mutex::new(SnowflakeClock::default())),
+        }
+    }
+
+    /// Changes the epoch, given as the time since the Unix epoch.
+    pub fn epoch(mut self, epoch: Duration) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    /// Returns the node ID.
+    pub fn node(&self) -> u16 {
+        self.node as u16
+    }
+}
+
+#[cfg(feature = "snowflake")]
+impl IdSource for Snowflake {
+    type Id = Id;
+
+    fn generate(&mut self, _db: &sled::Db) -> Result<Self::Id, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(self.epoch)
+            .as_millis() as u64;
+        let mut clock =
+            self.clock.lock().unwrap_or_else(PoisonError::into_inner);
+        if now > clock.millis {
+            clock.millis = now;
+            clock.sequence = 0;
+        } else if clock.sequence + 1 < 1 << SEQUENCE_BITS {
+            clock.sequence += 1;
+        } else {
+            clock.millis += 1;
+            clock.sequence = 0;
+        }
+        Ok(clock.millis << (NODE_BITS + SEQUENCE_BITS)
+            | self.node << SEQUENCE_BITS
+            | clock.sequence)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "snowflake")]
+    use super::Snowflake;
+    #[cfg(feature = "ulid")]
+    use super::Ulids;
+    #[cfg(feature = "uuid")]
+    use super::{UuidV4, UuidV7};
+    use super::{DbIds, IdSource};
+    #[cfg(feature = "snowflake")]
+    use crate::tree::Tree;
+    #[cfg(feature = "snowflake")]
+    use std::time::Duration;
+
+    /// Generates `count` IDs, sleeping between them so that time-ordered IDs
+    /// get different timestamps.
+    #[cfg(any(feature = "uuid", feature = "ulid"))]
+    fn generate_apart<S>(source: &mut S, count: usize) -> Vec<S::Id>
+    where
+        S: IdSource,
+    {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut ids = Vec::new();
+        for _ in 0..count {
+            ids.push(source.generate(&db).unwrap());
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        ids
+    }
+
+    #[test]
+    fn db_ids_are_unique() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let first = DbIds.generate(&db).unwrap();
+        assert!(DbIds.generate(&db).unwrap() > first);
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn uuids() {
+        let ids = generate_apart(&mut UuidV4, 4);
+        assert!(ids.iter().all(|id| id.get_version_num() == 4));
+        assert!(ids.windows(2).all(|pair| pair[0] != pair[1]));
+
+        let ids = generate_apart(&mut UuidV7, 4);
+        assert!(ids.iter().all(|id| id.get_version_num() == 7));
+        let encoded: Vec<_> =
+            ids.iter().map(|id| crate::encode(id).unwrap()).collect();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[cfg(feature = "ulid")]
+    #[test]
+    fn ulids() {
+        let ids = generate_apart(&mut Ulids, 4);
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        let encoded: Vec<_> =
+            ids.iter().map(|id| crate::encode(id).unwrap()).collect();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[cfg(feature = "snowflake")]
+    #[test]
+    fn snowflakes_increase_within_a_millisecond() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        // An epoch in the future keeps the clock at zero milliseconds.
+        let future = Duration::from_secs(u64::from(u32::MAX) * 8);
+        let mut source = Snowflake::new(5).epoch(future);
+        let mut clone = source.clone();
+        let mut ids = Vec::new();
+        for index in 0..5000 {
+            let source = if index % 2 == 0 { &mut source } else { &mut clone };
+            ids.push(source.generate(&db).unwrap());
+        }
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ids.iter().all(|id| id >> 12 & 0x3ff == 5));
+        assert_eq!(ids[0], 5 << 12 | 1);
+        assert_eq!(ids[4094], 5 << 12 | 4095);
+        assert_eq!(ids[4095], 1 << 22 | 5 << 12);
+
+        let mut other = Snowflake::new(6).epoch(future);
+        assert_eq!(other.generate(&db).unwrap(), 6 << 12 | 1);
+        let mut current = Snowflake::new(1023);
+        let id = current.generate(&db).unwrap();
+        assert!(id >> 22 > 0);
+        assert_eq!(id >> 12 & 0x3ff, 1023);
+    }
+
+    #[cfg(feature = "snowflake")]
+    #[test]
+    #[should_panic]
+    fn snowflake_node_out_of_range() {
+        Snowflake::new(1024);
+    }
+
+    #[cfg(feature = "snowflake")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn snowflake_collisions_are_retried() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Tree::<u64, String>::open(&db, "tree").await.unwrap();
+        let future = Duration::from_secs(u64::from(u32::MAX) * 8);
+        tree.insert(&(7 << 12 | 1), &"taken".to_string()).await.unwrap();
+
+        let source = Snowflake::new(7).epoch(future);
+        let ids = tree.id_builder().id_source(source).id_maker(|id| id);
+        let ids = ids.data_maker(|_| "new".to_string());
+        let (id, _) = ids.generate(&db).await.unwrap();
+        assert_eq!(id, 7 << 12 | 2);
+        let taken = tree.get(&(7 << 12 | 1)).await.unwrap();
+        assert_eq!(taken, Some("taken".into()));
+    }
+}
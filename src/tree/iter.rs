@@ -0,0 +1,118 @@
+//! Streams over the entries of a tree.
+
+use super::{expiry, InvalidEntry, Tree};
+use crate::{buffer, error::Error};
+use futures::stream::{self, Stream};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::task;
+
+/// An entry scanned from storage.
+enum Scanned<K, V> {
+    /// An entry which was decoded.
+    Valid(K, V),
+    /// An entry which could not be decoded.
+    Invalid(InvalidEntry),
+}
+
+/// A stream over the entries of a tree, in the order of their encoded keys,
+/// as returned by [`Tree::iter`] and [`Tree::range`].
+///
+/// Every entry yields its own result, so an entry which cannot be decoded
+/// yields an error, but does not stop the stream. Use [`Iter::skip_invalid`]
+/// to skip such entries instead. Expired entries are skipped.
+pub struct Iter<K, V, A = buffer::DefaultPool> {
+    tree: Tree<K, V, A>,
+    entries: sled::Iter,
+    operation: &'static str,
+}
+
+impl<K, V, A> Iter<K, V, A>
+where
+    for<'de> K: serde::Deserialize<'de>,
+    for<'de> V: serde::Deserialize<'de>,
+{
+    pub(crate) fn new(
+        tree: Tree<K, V, A>,
+        entries: sled::Iter,
+        operation: &'static str,
+    ) -> Self {
+        Self { tree, entries, operation }
+    }
+
+    /// Scans the next live entry. Blocking.
+    fn next_scanned(&mut self) -> Option<Result<Scanned<K, V>, Error>> {
+        let operation = self.operation;
+        let failed =
+            |tree: &Tree<K, V, A>, error| tree.failed(error, operation, None);
+        loop {
+            let (encoded_key, stored) = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(error) => {
+                    return Some(Err(failed(&self.tree, error.into())))
+                },
+            };
+            match self.tree.is_expired(&encoded_key, expiry::now()) {
+                Ok(true) => continue,
+                Ok(false) => (),
+                Err(error) => return Some(Err(failed(&self.tree, error))),
+            }
+            let result = self.tree.decode_key(&encoded_key).and_then(|key| {
+                let val = self.tree.decode_value(&encoded_key, &stored)?;
+                Ok((key, val))
+            });
+            return Some(Ok(match result {
+                Ok((key, val)) => Scanned::Valid(key, val),
+                Err(error) => Scanned::Invalid(InvalidEntry::new(
+                    encoded_key,
+                    stored,
+                    failed(&self.tree, error),
+                )),
+            }));
+        }
+    }
+
+    /// Converts this stream into one which skips entries that cannot be
+    /// decoded, giving them to `on_invalid` along with their raw bytes and the
+    /// error, so they can be logged. Only storage errors are yielded.
+    pub fn skip_invalid<F>(
+        mut self,
+        mut on_invalid: F,
+    ) -> impl Stream<Item = Result<(K, V), Error>>
+    where
+        F: FnMut(InvalidEntry),
+    {
+        stream::poll_fn(move |_| {
+            Poll::Ready(task::block_in_place(|| loop {
+                match self.next_scanned()? {
+                    Ok(Scanned::Valid(key, val)) => break Some(Ok((key, val))),
+                    Ok(Scanned::Invalid(entry)) => on_invalid(entry),
+                    Err(error) => break Some(Err(error)),
+                }
+            }))
+        })
+    }
+}
+
+impl<K, V, A> Stream for Iter<K, V, A>
+where
+    for<'de> K: serde::Deserialize<'de>,
+    for<'de> V: serde::Deserialize<'de>,
+    A: Unpin,
+{
+    type Item = Result<(K, V), Error>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        _ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let scanned = task::block_in_place(|| this.next_scanned());
+        Poll::Ready(scanned.map(|result| match result? {
+            Scanned::Valid(key, val) => Ok((key, val)),
+            Scanned::Invalid(entry) => Err(entry.into_error()),
+        }))
+    }
+}
@@ -0,0 +1,176 @@
+//! Persistent per-tree sequences.
+
+use super::companion_name;
+use crate::{
+    decode, encode,
+    error::{Error, ErrorKind},
+};
+use std::{
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// Key of the end of the last reserved block in the companion tree.
+const RESERVED_KEY: &[u8] = b"reserved";
+
+/// Numbers reserved but not yet handed out.
+#[derive(Debug, Default)]
+struct Block {
+    next: u64,
+    end: u64,
+}
+
+/// A sequence of strictly increasing numbers, reserved from a companion tree in
+/// blocks. Clones hand out numbers from the same block, while sequences opened
+/// separately reserve their own blocks.
+#[derive(Clone)]
+pub(crate) struct Sequence {
+    meta: sled::Tree,
+    block_size: u64,
+    block: Arc<Mutex<Block>>,
+}
+
+impl Sequence {
+    /// Opens the companion tree of the tree with the given name. Blocking.
+    pub(crate) fn open(
+        db: &sled::Db,
+        name: &[u8],
+        block_size: u64,
+    ) -> Result<Self, Error> {
+        let meta = db.open_tree(companion_name(name, "sequence"))?;
+        Ok(Self {
+            meta,
+            block_size: block_size.max(1),
+            block: Arc::new(Mutex::new(Block::default())),
+        })
+    }
+
+    /// Returns the next number, reserving a new block if the current one is
+    /// exhausted. Blocking.
+    pub(crate) fn next(&self) -> Result<u64, Error> {
+        let mut block =
+            self.block.lock().unwrap_or_else(PoisonError::into_inner);
+        if block.next == block.end {
+            let start = self.reserve(self.block_size)?;
+            block.next = start;
+            block.end = start + self.block_size;
+        }
+        let number = block.next;
+        block.next += 1;
+        Ok(number)
+    }
+
+    /// Returns the next `count` numbers, taking what is left of the current
+    /// block and reserving at most one new block, enlarged if needed.
+    /// Blocking.
+    pub(crate) fn next_many(&self, count: usize) -> Result<Vec<u64>, Error> {
+        let mut block =
+            self.block.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut numbers = Vec::with_capacity(count);
+        let available = (block.end - block.next).min(count as u64);
+        numbers.extend(block.next..block.next + available);
+        block.next += available;
+        let missing = count as u64 - available;
+        if missing > 0 {
+            let size = missing.max(self.block_size);
+            let start = self.reserve(size)?;
+            numbers.extend(start..start + missing);
+            block.next = start + missing;
+            block.end = start + size;
+        }
+        Ok(numbers)
+    }
+
+    /// Persists the reservation of a new block of `size` numbers, returning
+    /// its start. The reservation is flushed to disk before any of its numbers
+    /// is handed out, so numbers are never repeated, even after a crash.
+    /// Blocking.
+    fn reserve(&self, size: u64) -> Result<u64, Error> {
+        let mut current = self.meta.get(RESERVED_KEY)?;
+        loop {
+            let start: u64 = match &current {
+                Some(bytes) => decode(bytes)?,
+                None => 0,
+            };
+            let end = start.checked_add(size).ok_or_else(|| {
+                Error::new(ErrorKind::Custom("sequence exhausted".into()))
+            })?;
+            let swapped = self.meta.compare_and_swap(
+                RESERVED_KEY,
+                current.as_ref(),
+                Some(encode(end)?),
+            )?;
+            match swapped {
+                Ok(()) => {
+                    self.meta.flush()?;
+                    return Ok(start);
+                },
+                Err(error) => current = error.current,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Sequence {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Sequence")
+            .field("block_size", &self.block_size)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sequence;
+    use crate::tree::{OpenOptions, Tree};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handles_share_sequence() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let options = OpenOptions::new().sequence(10);
+        let first = Tree::<u64, ()>::open_with(&db, "tree", &options)
+            .await
+            .unwrap();
+        let second = first.clone();
+
+        assert_eq!(first.next_sequence().await.unwrap(), 0);
+        assert_eq!(second.next_sequence().await.unwrap(), 1);
+        assert_eq!(first.next_sequence().await.unwrap(), 2);
+
+        let ids = first.id_builder().id_source(first.sequence_ids().unwrap());
+        let ids = ids.id_maker(|id| id).data_maker(|_| ());
+        let (id, ()) = ids.generate(&db).await.unwrap();
+        assert_eq!(id, 3);
+    }
+
+    #[test]
+    fn next_many_reserves_one_block() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let sequence = Sequence::open(&db, b"tree", 4).unwrap();
+        assert_eq!(sequence.next().unwrap(), 0);
+        assert_eq!(sequence.next_many(2).unwrap(), [1, 2]);
+        assert_eq!(sequence.next_many(6).unwrap(), [3, 4, 5, 6, 7, 8]);
+        assert_eq!(sequence.next().unwrap(), 9);
+        assert!(sequence.next_many(0).unwrap().is_empty());
+
+        let reopened = Sequence::open(&db, b"tree", 4).unwrap();
+        assert_eq!(reopened.next().unwrap(), 13);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn separate_handles_reserve_separate_blocks() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let options = OpenOptions::new().sequence(10);
+        let first = Tree::<u64, ()>::open_with(&db, "tree", &options)
+            .await
+            .unwrap();
+        let second = Tree::<u64, ()>::open_with(&db, "tree", &options)
+            .await
+            .unwrap();
+
+        assert_eq!(first.next_sequence().await.unwrap(), 0);
+        assert_eq!(second.next_sequence().await.unwrap(), 10);
+        assert_eq!(first.next_sequence().await.unwrap(), 1);
+        assert_eq!(second.next_sequence().await.unwrap(), 11);
+    }
+}
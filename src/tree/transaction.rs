@@ -0,0 +1,390 @@
+//! Typed views of trees inside of a transaction, see
+//! [`IdBuilder::generate_in`](super::IdBuilder::generate_in).
+
+use super::{
+    encode_key, encode_value, expiry, ExpiryTx, LatestTx, Replace, Shared,
+    Stamp, Tree, TxResult,
+};
+use crate::{buffer, error::Error};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, TransactionalTree,
+        UnabortableTransactionError,
+    },
+    IVec,
+};
+use std::{borrow::Borrow, fmt};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Trees which can join the transaction of
+/// [`IdBuilder::generate_in`](super::IdBuilder::generate_in): a reference to a
+/// [`Tree`], or a tuple of up to four of them. Their views are typed
+/// transactional versions of the same shape, such as a [`TreeTx`] for a
+/// reference to a tree.
+pub trait TxTrees<'tree>: sealed::Sealed {
+    /// The views of the trees inside of the transaction.
+    type View;
+
+    /// Appends the storage trees which the transaction must include.
+    #[doc(hidden)]
+    fn storage(&self, trees: &mut Vec<&'tree sled::Tree>);
+
+    /// Makes the views from the transactional versions of the storage trees,
+    /// taking them off the front of `trees`.
+    #[doc(hidden)]
+    fn view(&self, trees: &mut &[TransactionalTree]) -> Self::View;
+}
+
+impl<K, V, A, S> sealed::Sealed for &Tree<K, V, A, S> {}
+
+impl<'tree, K, V, A, S> TxTrees<'tree> for &'tree Tree<K, V, A, S>
+where
+    S: Borrow<Shared>,
+{
+    type View = TreeTx<'tree, K, V, A, S>;
+
+    fn storage(&self, trees: &mut Vec<&'tree sled::Tree>) {
+        let tree: &'tree Tree<K, V, A, S> = self;
+        let shared = tree.shared();
+        trees.push(&shared.storage);
+        trees.extend(shared.companions());
+    }
+
+    fn view(&self, trees: &mut &[TransactionalTree]) -> Self::View {
+        let count = 1 + self.shared().companions().len();
+        let (own, rest) = trees.split_at(count);
+        *trees = rest;
+        TreeTx { tree: self, trees: own.to_vec() }
+    }
+}
+
+macro_rules! tuple_tx_trees {
+    ($($tree:ident),+) => {
+        impl<$($tree),+> sealed::Sealed for ($($tree,)+)
+        where
+            $($tree: sealed::Sealed),+
+        {
+        }
+
+        impl<'tree, $($tree),+> TxTrees<'tree> for ($($tree,)+)
+        where
+            $($tree: TxTrees<'tree>),+
+        {
+            type View = ($($tree::View,)+);
+
+            #[allow(non_snake_case)]
+            fn storage(&self, trees: &mut Vec<&'tree sled::Tree>) {
+                let ($($tree,)+) = self;
+                $($tree.storage(trees);)+
+            }
+
+            #[allow(non_snake_case)]
+            fn view(&self, trees: &mut &[TransactionalTree]) -> Self::View {
+                let ($($tree,)+) = self;
+                ($($tree.view(trees),)+)
+            }
+        }
+    };
+}
+
+tuple_tx_trees!(T0);
+tuple_tx_trees!(T0, T1);
+tuple_tx_trees!(T0, T1, T2);
+tuple_tx_trees!(T0, T1, T2, T3);
+
+/// A typed view of a [`Tree`] inside of a transaction. Keys and values are
+/// encoded, and values stored, exactly as the operations of the tree do, so
+/// the codec, envelopes and expiry of the tree are respected. Errors other
+/// than those of the transaction abort it, converted into the error type of
+/// the transaction.
+pub struct TreeTx<'tree, K, V, A = buffer::DefaultPool, S = Shared> {
+    tree: &'tree Tree<K, V, A, S>,
+    trees: Vec<TransactionalTree>,
+}
+
+impl<'tree, K, V, A, S> TreeTx<'tree, K, V, A, S>
+where
+    S: Borrow<Shared>,
+{
+    fn storage(&self) -> &TransactionalTree {
+        &self.trees[0]
+    }
+
+    fn latest(&self) -> Option<LatestTx<'_>> {
+        self.tree.shared().companion_views(&self.trees[1..]).0
+    }
+
+    fn expiry(&self) -> Option<ExpiryTx<'_>> {
+        self.tree.shared().companion_views(&self.trees[1..]).1
+    }
+
+    /// Returns the stamp of a write of the entry with the given encoded key.
+    fn stamp(
+        &self,
+        encoded_key: &[u8],
+    ) -> Result<Stamp, UnabortableTransactionError> {
+        let latest = match self.latest() {
+            Some(latest) => latest.get(encoded_key)?,
+            None => 0,
+        };
+        Ok(Stamp { now: expiry::now(), latest })
+    }
+
+    /// Gets an encoded value, unless it has expired.
+    fn get_encoded(
+        &self,
+        encoded_key: &[u8],
+        now: u64,
+    ) -> Result<Option<IVec>, UnabortableTransactionError> {
+        let stored = self.storage().get(encoded_key)?;
+        if let Some(expiry) = self.expiry() {
+            if stored.is_some() && expiry.is_expired(encoded_key, now)? {
+                return Ok(None);
+            }
+        }
+        Ok(stored)
+    }
+
+    /// Aborts the transaction with the given error, recording the operation,
+    /// the tree and the encoded key.
+    fn abort<E>(
+        &self,
+        error: Error,
+        operation: &'static str,
+        encoded_key: Option<&[u8]>,
+    ) -> ConflictableTransactionError<E>
+    where
+        E: From<Error>,
+    {
+        let error = self.tree.failed(error, operation, encoded_key);
+        ConflictableTransactionError::Abort(E::from(error))
+    }
+
+    fn decode_old<E>(
+        &self,
+        old: Option<IVec>,
+        operation: &'static str,
+        encoded_key: &[u8],
+    ) -> TxResult<Option<V>, E>
+    where
+        for<'de> V: serde::Deserialize<'de>,
+        E: From<Error>,
+    {
+        match old {
+            Some(old) => self
+                .tree
+                .decode_value(encoded_key, &old)
+                .map(Some)
+                .map_err(|error| {
+                    self.abort(error, operation, Some(encoded_key))
+                }),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the value associated with the given `key`, returning `None` if key
+    /// is not found. Serializes key using a buffer from the allocation of the
+    /// tree.
+    pub fn get<Q, E>(&self, key: &Q) -> TxResult<Option<V>, E>
+    where
+        for<'de> V: serde::Deserialize<'de>,
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+        A: buffer::Allocation + Clone,
+        E: From<Error>,
+    {
+        let mut allocation = self.tree.allocation();
+        let mut key_buf = allocation.make();
+        let result = match encode_key(&mut key_buf, key) {
+            Ok(encoded_key) => self
+                .get_encoded(encoded_key, expiry::now())
+                .map_err(ConflictableTransactionError::from)
+                .and_then(|old| self.decode_old(old, "get", encoded_key)),
+            Err(error) => Err(self.abort(error, "get", None)),
+        };
+        allocation.save(key_buf);
+        result
+    }
+
+    fn insert_raw<E>(
+        &self,
+        key: &K,
+        val: &V,
+        key_buf: &mut buffer::Buffer,
+        val_buf: &mut buffer::Buffer,
+    ) -> TxResult<Option<V>, E>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+        E: From<Error>,
+    {
+        let encoded_key = encode_key(key_buf, key)
+            .map_err(|error| self.abort(error, "insert", None))?;
+        let abort = |error| self.abort(error, "insert", Some(encoded_key));
+        let codec = &self.tree.shared().codec;
+        let encoded_value = encode_value(val_buf, val, codec).map_err(abort)?;
+        let stamp = self.stamp(encoded_key)?;
+        let old = self.get_encoded(encoded_key, stamp.now)?;
+        let replace = self
+            .tree
+            .put_value(encoded_key, old.as_deref(), encoded_value, stamp)
+            .map_err(abort)?;
+        if let Replace::Put(stored) = replace {
+            self.storage().insert(encoded_key, &*stored)?;
+            if let Some(latest) = self.latest() {
+                latest.put(encoded_key, &stored)?;
+            }
+        }
+        if let Some(expiry) = self.expiry() {
+            expiry.set_deadline(encoded_key, None)?;
+        }
+        self.decode_old(old, "insert", encoded_key)
+    }
+
+    /// Inserts key and value returning `None` if key is new, `Some(old_value)`
+    /// if the key already exists (and replacing its data). Inserting this way
+    /// removes any time-to-live of the entry. Serializes key and value using a
+    /// buffer from the allocation of the tree.
+    pub fn insert<E>(&self, key: &K, val: &V) -> TxResult<Option<V>, E>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+        A: buffer::Allocation + Clone,
+        E: From<Error>,
+    {
+        let mut allocation = self.tree.allocation();
+        let mut key_buf = allocation.make();
+        let mut val_buf = allocation.make();
+        let result = self.insert_raw(key, val, &mut key_buf, &mut val_buf);
+        allocation.save(key_buf);
+        allocation.save(val_buf);
+        result
+    }
+
+    /// Removes the value associated with the given `key`, returning `None` if
+    /// key is not found. Serializes key using a buffer from the allocation of
+    /// the tree.
+    pub fn remove<Q, E>(&self, key: &Q) -> TxResult<Option<V>, E>
+    where
+        for<'de> V: serde::Deserialize<'de>,
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+        A: buffer::Allocation + Clone,
+        E: From<Error>,
+    {
+        let mut allocation = self.tree.allocation();
+        let mut key_buf = allocation.make();
+        let result = match encode_key(&mut key_buf, key) {
+            Ok(encoded_key) => self
+                .stamp(encoded_key)
+                .and_then(|stamp| {
+                    let old = self.get_encoded(encoded_key, stamp.now)?;
+                    self.storage().remove(encoded_key)?;
+                    if let (Some(latest), Some(_)) = (self.latest(), &old) {
+                        latest.remove(encoded_key, stamp.latest)?;
+                    }
+                    if let Some(expiry) = self.expiry() {
+                        expiry.set_deadline(encoded_key, None)?;
+                    }
+                    Ok(old)
+                })
+                .map_err(ConflictableTransactionError::from)
+                .and_then(|old| self.decode_old(old, "remove", encoded_key)),
+            Err(error) => Err(self.abort(error, "remove", None)),
+        };
+        allocation.save(key_buf);
+        result
+    }
+}
+
+impl<'tree, K, V, A, S> fmt::Debug for TreeTx<'tree, K, V, A, S>
+where
+    A: fmt::Debug,
+    S: Borrow<Shared>,
+{
+    fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+        fmtr.debug_struct("TreeTx").field("tree", self.tree).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        error::{Error, ErrorKind},
+        tree::{OpenOptions, Tree},
+    };
+    use sled::transaction::ConflictableTransactionError;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn generate_in_typed_trees() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let options = OpenOptions::new().sequence(1);
+        let users = Tree::<u64, String>::open_with(&db, "users", &options)
+            .await
+            .unwrap();
+        let options = OpenOptions::new().expiry(true).envelope(true);
+        let names = Tree::<String, u64>::open_with(&db, "names", &options)
+            .await
+            .unwrap();
+        let count = Tree::<(), u64>::open(&db, "count").await.unwrap();
+        names
+            .insert_with_ttl(&"old".to_string(), &7, Duration::from_millis(0))
+            .await
+            .unwrap();
+
+        let create = |name: &'static str| {
+            let ids = users.id_builder();
+            let ids = ids.id_source(users.sequence_ids().unwrap());
+            let ids = ids.id_maker(|id| id).data_maker(move |_| name.into());
+            ids.generate_in(&db, (&names, &count), |id, name, (names, count)| {
+                if names.get(name)?.is_some() {
+                    let error = Error::conflict("name is taken");
+                    return Err(ConflictableTransactionError::Abort(error));
+                }
+                names.insert(name, id)?;
+                let total = count.get(&())?.unwrap_or(0);
+                count.insert(&(), &(total + 1))?;
+                Ok(())
+            })
+        };
+
+        let (id, name) = create("old").await.unwrap();
+        assert_eq!((id, name.as_str()), (0, "old"));
+        let (id, _) = create("new").await.unwrap();
+        assert_eq!(id, 1);
+        let error = create("new").await.unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::Conflict(_)), "{}", error);
+
+        assert!(!users.contains_key(&2).await.unwrap());
+        assert_eq!(count.get(&()).await.unwrap(), Some(2));
+        let (id, meta) =
+            names.get_meta(&"old".to_string()).await.unwrap().unwrap();
+        assert_eq!(id, 0);
+        assert_eq!(meta.version(), 2);
+        assert_eq!(names.get(&"new".to_string()).await.unwrap(), Some(1));
+
+        let ids = users.id_builder();
+        let ids = ids.id_source(users.sequence_ids().unwrap());
+        let ids = ids.id_maker(|id| id).async_data_maker(|&id| {
+            let users = users.clone();
+            async move {
+                users.insert(&id, &"taken".to_string()).await.unwrap();
+                "raced".to_string()
+            }
+        });
+        let (id, name) = ids
+            .generate_in(&db, &names, |id, name, names| {
+                names.insert(name, id)?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(users.get(&id).await.unwrap(), Some(name.clone()));
+        assert_eq!(users.get(&(id - 1)).await.unwrap(), Some("taken".into()));
+        assert_eq!(names.get(&name).await.unwrap(), Some(id));
+    }
+}
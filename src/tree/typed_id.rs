@@ -0,0 +1,173 @@
+//! IDs tagged with the type of the entries they identify.
+
+use super::Id;
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    num::ParseIntError,
+    str::FromStr,
+};
+
+/// An [`Id`] tagged with the type `T` of the entries it identifies, so that
+/// IDs of different kinds of entries cannot be mixed up, e.g. in a
+/// `Tree<TypedId<User>, User>`. It is encoded exactly as the plain [`Id`], so
+/// a tree keyed by [`Id`] can switch to [`TypedId`] without migrating data.
+///
+/// Use [`IdBuilder::typed_id_maker`](super::IdBuilder::typed_id_maker) to
+/// generate them.
+pub struct TypedId<T> {
+    id: Id,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedId<T> {
+    /// Tags the given plain ID.
+    pub const fn new(id: Id) -> Self {
+        Self { id, _marker: PhantomData }
+    }
+
+    /// Returns the plain ID.
+    pub const fn get(self) -> Id {
+        self.id
+    }
+}
+
+impl<T> From<TypedId<T>> for Id {
+    fn from(typed: TypedId<T>) -> Self {
+        typed.id
+    }
+}
+
+impl<T> Clone for TypedId<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for TypedId<T> {}
+
+impl<T> PartialEq for TypedId<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<T> Eq for TypedId<T> {}
+
+impl<T> PartialOrd for TypedId<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for TypedId<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+impl<T> Hash for TypedId<T> {
+    fn hash<H>(&self, state: &mut H)
+    where
+        H: Hasher,
+    {
+        self.id.hash(state)
+    }
+}
+
+impl<T> fmt::Debug for TypedId<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "TypedId({})", self.id)
+    }
+}
+
+impl<T> fmt::Display for TypedId<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.id, fmt)
+    }
+}
+
+impl<T> FromStr for TypedId<T> {
+    type Err = ParseIntError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        input.parse().map(Self::new)
+    }
+}
+
+impl<T> serde::Serialize for TypedId<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.id.serialize(serializer)
+    }
+}
+
+impl<'de, T> serde::Deserialize<'de> for TypedId<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Id::deserialize(deserializer).map(Self::new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TypedId;
+    use crate::tree::{Id, Tree};
+    use futures::stream::StreamExt;
+
+    struct User;
+
+    #[test]
+    fn serde_transparent_and_ordered() {
+        let ids: Vec<Id> = vec![0, 1, 127, 128, 300, 70_000, u64::MAX];
+        for pair in ids.windows(2) {
+            let (low, high) = (TypedId::<User>::new(pair[0]), pair[1]);
+            let high = TypedId::<User>::new(high);
+            assert!(low < high);
+            let encoded_low = crate::encode(low).unwrap();
+            let encoded_high = crate::encode(high).unwrap();
+            assert_eq!(encoded_low, crate::encode(pair[0]).unwrap());
+            assert!(encoded_low < encoded_high);
+            let decoded: TypedId<User> = crate::decode(&encoded_low).unwrap();
+            assert_eq!(decoded, low);
+        }
+
+        let id = TypedId::<User>::new(42);
+        assert_eq!(id.to_string(), "42");
+        assert_eq!("42".parse::<TypedId<User>>().unwrap(), id);
+        assert_eq!(Id::from(id), 42);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tree_keyed_by_typed_ids() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let plain = Tree::<Id, String>::open(&db, "users").await.unwrap();
+        plain.insert(&7, &"old".to_string()).await.unwrap();
+
+        let users = Tree::<TypedId<User>, String>::open(&db, "users")
+            .await
+            .unwrap();
+        let old = TypedId::new(7);
+        assert_eq!(users.get(&old).await.unwrap(), Some("old".into()));
+        let ids = users.id_builder().typed_id_maker();
+        let ids = ids.data_maker(|_| "new".to_string());
+        let (id, _) = ids.generate(&db).await.unwrap();
+        assert_eq!(users.get(&id).await.unwrap(), Some("new".into()));
+        assert_eq!(plain.get(&id.get()).await.unwrap(), Some("new".into()));
+
+        let keys: Vec<_> = users
+            .iter()
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>()
+            .await;
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+    }
+}
@@ -0,0 +1,144 @@
+//! Reports of the verification of the entries of a tree, and entries which
+//! could not be decoded.
+
+use crate::error::{Error, ErrorKind};
+use sled::IVec;
+
+/// An entry which could not be decoded, with its raw bytes.
+#[derive(Debug)]
+pub struct InvalidEntry {
+    key: IVec,
+    value: IVec,
+    error: Error,
+    quarantined: bool,
+}
+
+impl InvalidEntry {
+    pub(crate) fn new(key: IVec, value: IVec, error: Error) -> Self {
+        Self { key, value, error, quarantined: false }
+    }
+
+    /// Returns the raw bytes of the key.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns the raw bytes of the stored value.
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Returns the error found decoding the entry.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    /// Converts this entry into the error found decoding it.
+    pub fn into_error(self) -> Error {
+        self.error
+    }
+
+    /// Returns whether the entry was moved to the quarantine tree by
+    /// [`Tree::repair`](super::Tree::repair).
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined
+    }
+}
+
+/// Report of the verification of a tree. See
+/// [`Tree::verify`](super::Tree::verify).
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    entries: usize,
+    undecodable: Vec<InvalidEntry>,
+    corrupted: Vec<InvalidEntry>,
+    non_canonical: Vec<IVec>,
+}
+
+impl VerifyReport {
+    /// Returns the number of entries scanned.
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// Returns the entries whose key or value could not be decoded, other than
+    /// checksum mismatches.
+    pub fn undecodable(&self) -> &[InvalidEntry] {
+        &self.undecodable
+    }
+
+    /// Returns the entries whose value does not match its checksum.
+    pub fn corrupted(&self) -> &[InvalidEntry] {
+        &self.corrupted
+    }
+
+    /// Returns the raw keys which decode into a key whose encoding differs
+    /// from the stored bytes, e.g. because they were written with another
+    /// encoding. Lookups by the decoded key do not find such entries.
+    pub fn non_canonical(&self) -> &[IVec] {
+        &self.non_canonical
+    }
+
+    /// Tests whether no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.undecodable.is_empty()
+            && self.corrupted.is_empty()
+            && self.non_canonical.is_empty()
+    }
+
+    /// Counts a scanned entry.
+    pub(crate) fn count(&mut self) {
+        self.entries += 1;
+    }
+
+    /// Records an entry which failed verification.
+    pub(crate) fn invalid(
+        &mut self,
+        key: IVec,
+        value: IVec,
+        error: Error,
+        quarantined: bool,
+    ) {
+        let corrupted = matches!(error.kind(), ErrorKind::Corruption(_));
+        let entry = InvalidEntry { key, value, error, quarantined };
+        if corrupted {
+            self.corrupted.push(entry);
+        } else {
+            self.undecodable.push(entry);
+        }
+    }
+
+    /// Records a non-canonical key.
+    pub(crate) fn non_canonical_key(&mut self, key: IVec) {
+        self.non_canonical.push(key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tree::Tree;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn verify_string_keys() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Tree::<String, i64>::open(&db, "tree").await.unwrap();
+        for (val, key) in ["b", "aa", "", "ba", "a"].iter().enumerate() {
+            tree.insert(&key.to_string(), &-(val as i64)).await.unwrap();
+        }
+
+        let report = tree.verify().await.unwrap();
+        assert_eq!(report.entries(), 5);
+        assert!(report.is_ok(), "{:?}", report);
+
+        let storage = db.open_tree("tree").unwrap();
+        let non_canonical = [251, 0, 1, b'c'];
+        let stored = storage.get(crate::encode("a").unwrap()).unwrap().unwrap();
+        storage.insert(non_canonical, stored).unwrap();
+
+        let report = tree.verify().await.unwrap();
+        assert_eq!(report.entries(), 6);
+        assert_eq!(report.non_canonical(), [&non_canonical[..]]);
+        assert!(report.undecodable().is_empty());
+        assert!(report.corrupted().is_empty());
+    }
+}
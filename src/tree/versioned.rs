@@ -0,0 +1,862 @@
+//! Trees keeping the history of the values of their entries.
+//!
+//! Besides the tree holding the current entries, whose envelopes keep the
+//! latest version of each key even after an entry is removed, a versioned tree
+//! uses a companion tree: the history, mapping an encoded key followed by a big
+//! endian `u64` version to a record of that version. A record is a tag byte,
+//! `1` if the entry was written and `0` if it was removed, followed by an
+//! envelope, whose value is empty for removals.
+
+use super::{
+    companion_name, encode_key, encode_value, envelope, expiry, CallOptions,
+    Iter, Metadata, OpenOptions, Ref, Replace, Shared, Stamp, Tree, TxResult,
+};
+use crate::{
+    buffer::{self, Buffer},
+    codec::{Codec, Context},
+    decode_limited,
+    error::{Error, Stage, Unsupported},
+};
+use futures::stream::{self, Stream};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree},
+    IVec,
+};
+use std::{
+    borrow::{Borrow, Cow},
+    convert::TryInto,
+    fmt,
+    num::NonZeroU64,
+    ops::RangeBounds,
+    time::SystemTime,
+};
+use tokio::task;
+
+/// Tag of a record of a written entry.
+const WRITTEN: u8 = 1;
+
+/// Tag of a record of a removed entry.
+const REMOVED: u8 = 0;
+
+/// Size of the header of a record: the tag and the envelope header.
+const RECORD_HEADER_SIZE: usize = 1 + envelope::HEADER_SIZE;
+
+fn history_key(encoded_key: &[u8], version: u64) -> Vec<u8> {
+    let mut history_key = Vec::with_capacity(encoded_key.len() + 8);
+    history_key.extend_from_slice(encoded_key);
+    history_key.extend_from_slice(&version.to_be_bytes());
+    history_key
+}
+
+/// Returns the version in a key of the history.
+fn history_version(history_key: &[u8]) -> u64 {
+    let start = history_key.len().saturating_sub(8);
+    u64::from_be_bytes(history_key[start..].try_into().unwrap_or([0; 8]))
+}
+
+fn make_record(
+    codec: &Codec,
+    context: Context,
+    metadata: Metadata,
+    encoded_value: Option<&[u8]>,
+) -> Result<Vec<u8>, Error> {
+    let mut header = [REMOVED; RECORD_HEADER_SIZE];
+    if encoded_value.is_some() {
+        header[0] = WRITTEN;
+    }
+    header[1..].copy_from_slice(&envelope::header(metadata));
+    let record = codec.seal(context, &header, encoded_value.unwrap_or(&[]))?;
+    Ok(record.into_owned())
+}
+
+/// Metadata and encoded value, if the entry was written, of a record.
+type Record<'record> = (Metadata, Option<Cow<'record, [u8]>>);
+
+/// Splits a record into its metadata and encoded value, still compressed, if
+/// the entry was written.
+fn open_record<'record>(
+    codec: &Codec,
+    context: Context,
+    record: &'record [u8],
+) -> Result<Record<'record>, Error> {
+    let encoded_value =
+        codec.open_payload(context, record, RECORD_HEADER_SIZE)?;
+    let (metadata, _) = envelope::open(&record[1..])?;
+    if record[0] == WRITTEN {
+        Ok((metadata, Some(encoded_value)))
+    } else {
+        Ok((metadata, None))
+    }
+}
+
+/// Decompresses and decodes a value from a record, within the decode limit of
+/// the codec.
+fn decode_value<V>(codec: &Codec, encoded_value: &[u8]) -> Result<V, Error>
+where
+    for<'de> V: serde::Deserialize<'de>,
+{
+    decode_limited(&codec.decompress(encoded_value)?, codec.limit())
+}
+
+fn decode_record<V>(
+    codec: &Codec,
+    context: Context,
+    record: &[u8],
+) -> Result<(Metadata, Option<V>), Error>
+where
+    for<'de> V: serde::Deserialize<'de>,
+{
+    let (metadata, encoded_value) = open_record(codec, context, record)?;
+    match encoded_value {
+        Some(encoded_value) => {
+            Ok((metadata, Some(decode_value(codec, &encoded_value)?)))
+        },
+        None => Ok((metadata, None)),
+    }
+}
+
+/// How many versions of each key a [`Versioned`] tree keeps in its history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    /// Keeps every version.
+    All,
+    /// Keeps only the given number of most recent versions.
+    Last(NonZeroU64),
+}
+
+/// A persistent key-value structure which keeps the history of the values of
+/// each key, allowing reads of past values and reverting to them.
+///
+/// Every write, including removals, creates a new version of the entry, with
+/// versions increasing even across removals. The current entries are kept in a
+/// [`Tree`] with envelopes enabled, so each entry also has its [`Metadata`],
+/// whose version is the version in the history.
+pub struct Versioned<K, V, A = buffer::DefaultPool, S = Shared> {
+    tree: Tree<K, V, A, S>,
+    history: sled::Tree,
+    retention: Retention,
+}
+
+impl<K, V> Versioned<K, V> {
+    /// Opens this tree from a database, keeping versions according to the
+    /// given retention.
+    pub async fn open<T>(
+        db: &sled::Db,
+        name: T,
+        retention: Retention,
+    ) -> Result<Self, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        Self::open_with(db, name, &OpenOptions::default(), retention).await
+    }
+}
+
+impl<K, V, A> Versioned<K, V, A> {
+    /// Opens this tree from a database, with the given options, keeping
+    /// versions according to the given retention. Envelopes are always
+    /// enabled, and expiry is not supported. After reducing the retention,
+    /// the extra versions of an entry are kept until the entry is written
+    /// again, which removes every version no longer retained.
+    pub async fn open_with<T>(
+        db: &sled::Db,
+        name: T,
+        options: &OpenOptions<A>,
+        retention: Retention,
+    ) -> Result<Self, Error>
+    where
+        T: AsRef<[u8]>,
+        A: Clone,
+    {
+        if options.expiry {
+            let error = Unsupported::new("Versioned::open", "expiry disabled");
+            return Err(error.into());
+        }
+        let name = name.as_ref();
+        let options = options.clone().envelope(true);
+        let tree = Tree::open_with(db, name, &options).await?;
+        task::block_in_place(|| {
+            let history = db.open_tree(companion_name(name, "history"))?;
+            Ok(Self { tree, history, retention })
+        })
+    }
+}
+
+impl<K, V, A, S> Versioned<K, V, A, S>
+where
+    S: Borrow<Shared>,
+{
+    /// Re-encrypts every value not encrypted with the current key, both of
+    /// current entries and of the history, returning the number of
+    /// re-encrypted values. See [`Tree::reencrypt`].
+    #[cfg(feature = "chacha20poly1305")]
+    pub async fn reencrypt(&self) -> Result<usize, Error> {
+        self.tree.require_encryption("Versioned::reencrypt")?;
+        let current = self.tree.reencrypt().await?;
+        let history = task::block_in_place(|| {
+            super::reencrypt_storage(
+                &self.history,
+                self.codec(),
+                RECORD_HEADER_SIZE,
+            )
+        })
+        .map_err(|error| error.in_operation("reencrypt", &self.history))?;
+        Ok(current + history)
+    }
+
+    /// Returns the codec of the current entries, also used by the history.
+    fn codec(&self) -> &Codec {
+        &self.tree.shared().codec
+    }
+
+    /// Returns a read-only view of the current entries. Writes must go
+    /// through this versioned tree so they are kept in the history.
+    pub fn current(&self) -> Current<'_, K, V, A, S> {
+        Current { tree: &self.tree }
+    }
+
+    /// Returns a handle to this versioned tree whose operations use the given
+    /// options instead, borrowing the current entries from this tree. See
+    /// [`Tree::with_options`].
+    pub fn with_options<A0>(
+        &self,
+        options: CallOptions<A0>,
+    ) -> Versioned<K, V, A0, &Shared> {
+        Versioned {
+            tree: self.tree.with_options(options),
+            history: self.history.clone(),
+            retention: self.retention,
+        }
+    }
+
+    /// Returns the keys in the history of the versions of the entry with the
+    /// given key, if only some versions are retained, so that writes can
+    /// remove the versions no longer retained. Empty if every version is
+    /// retained. Blocking.
+    fn kept_keys(&self, encoded_key: &[u8]) -> Result<Vec<IVec>, Error> {
+        if self.retention == Retention::All {
+            return Ok(Vec::new());
+        }
+        let mut kept = Vec::new();
+        for entry in self.history.scan_prefix(encoded_key) {
+            let (kept_key, _) = entry?;
+            if kept_key.len() == encoded_key.len() + 8 {
+                kept.push(kept_key);
+            }
+        }
+        Ok(kept)
+    }
+
+    /// Records a write of the entry with the given key, or a removal if
+    /// `encoded_value` is `None`, inside of a transaction whose extra tree is
+    /// the history, removing the versions in `kept` no longer retained. The
+    /// new version follows the latest version in `stamp`. Returns how to
+    /// replace the current entry and the metadata of the new version.
+    fn record(
+        &self,
+        trees: &[TransactionalTree],
+        encoded_key: &[u8],
+        kept: &[IVec],
+        old: Option<&[u8]>,
+        encoded_value: Option<&[u8]>,
+        stamp: Stamp,
+    ) -> TxResult<(Replace<'static>, Option<Metadata>)> {
+        let history = &trees[0];
+        if old.is_none() && encoded_value.is_none() {
+            return Ok((Replace::Keep, None));
+        }
+
+        let created_at = match old {
+            Some(old) => self
+                .tree
+                .open_meta(encoded_key, old)
+                .map_err(ConflictableTransactionError::Abort)?
+                .created_at_millis(),
+            None => stamp.now,
+        };
+        let version = stamp.latest.saturating_add(1);
+        let metadata = Metadata::new(created_at, stamp.now, version);
+
+        let key = history_key(encoded_key, version);
+        let context = Context::new(&self.history, &key);
+        let record =
+            make_record(self.codec(), context, metadata, encoded_value)
+                .map_err(ConflictableTransactionError::Abort)?;
+        history.insert(key, record)?;
+        if let Retention::Last(count) = self.retention {
+            if let Some(expired) = version.checked_sub(count.get()) {
+                for kept_key in kept {
+                    if history_version(kept_key) <= expired {
+                        history.remove(kept_key)?;
+                    }
+                }
+            }
+        }
+
+        let replace = match encoded_value {
+            Some(encoded_value) => {
+                let stored = self
+                    .tree
+                    .seal_value(encoded_key, Some(metadata), encoded_value)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                Replace::Put(Cow::Owned(stored.into_owned()))
+            },
+            None => Replace::Remove,
+        };
+        Ok((replace, Some(metadata)))
+    }
+
+    /// Writes the entry with the given key, or removes it if `encoded_value`
+    /// is `None`, returning the previous stored value. Blocking.
+    fn write_encoded(
+        &self,
+        encoded_key: &[u8],
+        encoded_value: Option<&[u8]>,
+    ) -> Result<Option<IVec>, Error> {
+        let kept = self.kept_keys(encoded_key)?;
+        self.tree.replace_encoded_in(
+            encoded_key,
+            None,
+            &[&self.history],
+            |old, stamp, trees| {
+                let (replace, _) = self.record(
+                    trees,
+                    encoded_key,
+                    &kept,
+                    old,
+                    encoded_value,
+                    stamp,
+                )?;
+                Ok(replace)
+            },
+        )
+    }
+
+    async fn insert_raw(
+        &self,
+        key: &K,
+        val: &V,
+        key_buf: &mut Buffer,
+        val_buf: &mut Buffer,
+    ) -> Result<Option<V>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+    {
+        let encoded_key = encode_key(key_buf, key)?;
+        let encoded_value = encode_value(val_buf, val, self.codec())?;
+        let encoded = task::block_in_place(|| {
+            self.write_encoded(encoded_key, Some(encoded_value))
+        })?;
+        match encoded {
+            Some(encoded_val) => {
+                Ok(Some(self.tree.decode_value(encoded_key, &encoded_val)?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Inserts key and value as a new version, returning `None` if key is new,
+    /// `Some(old_value)` if the key already exists (and replacing its data).
+    /// Serializes key and value using a buffer from the allocation of the tree.
+    pub async fn insert(&self, key: &K, val: &V) -> Result<Option<V>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Serialize + serde::Deserialize<'de>,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.tree.allocation();
+        let mut key_buf = allocation.make();
+        let mut val_buf = allocation.make();
+        let result = self
+            .tree
+            .write(self.insert_raw(key, val, &mut key_buf, &mut val_buf))
+            .await
+            .map_err(|error| {
+                self.tree.failed(error, "insert", Some(key_buf.bytes()))
+            });
+        allocation.save(key_buf);
+        allocation.save(val_buf);
+        result
+    }
+
+    async fn remove_raw(
+        &self,
+        key: &K,
+        key_buf: &mut Buffer,
+    ) -> Result<Option<V>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Deserialize<'de>,
+    {
+        let encoded_key = encode_key(key_buf, key)?;
+        let encoded =
+            task::block_in_place(|| self.write_encoded(encoded_key, None))?;
+        match encoded {
+            Some(encoded_val) => {
+                Ok(Some(self.tree.decode_value(encoded_key, &encoded_val)?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the value associated with the given `key`, recording the removal
+    /// as a new version, and returning `None` if key is not found. Serializes
+    /// key using a buffer from the allocation of the tree.
+    pub async fn remove(&self, key: &K) -> Result<Option<V>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Deserialize<'de>,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.tree.allocation();
+        let mut key_buf = allocation.make();
+        let result = self
+            .tree
+            .write(self.remove_raw(key, &mut key_buf))
+            .await
+            .map_err(|error| {
+                self.tree.failed(error, "remove", Some(key_buf.bytes()))
+            });
+        allocation.save(key_buf);
+        result
+    }
+
+    async fn get_as_of_raw(
+        &self,
+        key: &K,
+        time: SystemTime,
+        key_buf: &mut Buffer,
+    ) -> Result<Option<V>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Deserialize<'de>,
+    {
+        let encoded_key = encode_key(key_buf, key)?;
+        let time = expiry::millis(time);
+        task::block_in_place(|| {
+            for entry in self.history.scan_prefix(encoded_key).rev() {
+                let (history_key, record) = entry?;
+                let in_history = |error: Error| {
+                    error
+                        .in_stage(Stage::ValueDecode)
+                        .at(&self.history, &history_key)
+                };
+                let context = Context::new(&self.history, &history_key);
+                let (metadata, encoded_value) =
+                    open_record(self.codec(), context, &record)
+                        .map_err(in_history)?;
+                if metadata.updated_at_millis() <= time {
+                    return match encoded_value {
+                        Some(encoded_value) => {
+                            decode_value(self.codec(), &encoded_value)
+                                .map(Some)
+                                .map_err(in_history)
+                        },
+                        None => Ok(None),
+                    };
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    /// Gets the value the given `key` had at the given time, returning `None`
+    /// if key did not exist then, or if the version current at that time is no
+    /// longer retained. Serializes key using a buffer from the allocation of
+    /// the tree.
+    pub async fn get_as_of(
+        &self,
+        key: &K,
+        time: SystemTime,
+    ) -> Result<Option<V>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Deserialize<'de>,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.tree.allocation();
+        let mut key_buf = allocation.make();
+        let result = self
+            .get_as_of_raw(key, time, &mut key_buf)
+            .await
+            .map_err(|error| {
+                self.tree.failed(error, "get_as_of", Some(key_buf.bytes()))
+            });
+        allocation.save(key_buf);
+        result
+    }
+
+    /// Streams the retained versions of the given `key`, from the oldest to the
+    /// newest, with the value of each version, or `None` if the version is a
+    /// removal. Serializes key using a buffer from the allocation of the tree.
+    pub fn history(
+        &self,
+        key: &K,
+    ) -> Result<impl Stream<Item = Result<(Metadata, Option<V>), Error>>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Deserialize<'de>,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.tree.allocation();
+        let mut key_buf = allocation.make();
+        let result = encode_key(&mut key_buf, key)
+            .map(|encoded_key| self.history.scan_prefix(encoded_key))
+            .map_err(|error| self.tree.failed(error, "history", None));
+        allocation.save(key_buf);
+        let entries = result?;
+        let codec = self.codec().clone();
+        let history = self.history.clone();
+
+        Ok(stream::unfold(entries, move |mut entries| {
+            let codec = codec.clone();
+            let history = history.clone();
+            async move {
+                let entry = task::block_in_place(|| entries.next())?;
+                let item = match entry {
+                    Ok((history_key, record)) => {
+                        let context = Context::new(&history, &history_key);
+                        decode_record(&codec, context, &record).map_err(
+                            |error| {
+                                error
+                                    .in_stage(Stage::ValueDecode)
+                                    .at(&history, &history_key)
+                            },
+                        )
+                    },
+                    Err(error) => Err(Error::from(error)),
+                };
+                let item = item
+                    .map_err(|error| error.in_operation("history", &history));
+                Some((item, entries))
+            }
+        }))
+    }
+
+    async fn revert_raw(
+        &self,
+        key: &K,
+        version: u64,
+        key_buf: &mut Buffer,
+    ) -> Result<Option<Metadata>, Error>
+    where
+        K: serde::Serialize,
+    {
+        let encoded_key = encode_key(key_buf, key)?;
+        let target = history_key(encoded_key, version);
+        let mut reverted = None;
+        task::block_in_place(|| {
+            let kept = self.kept_keys(encoded_key)?;
+            self.tree.replace_encoded_in(
+                encoded_key,
+                None,
+                &[&self.history],
+                |old, stamp, trees| {
+                    reverted = None;
+                    let record = match trees[0].get(&target)? {
+                        Some(record) => record,
+                        None => return Ok(Replace::Keep),
+                    };
+                    let context = Context::new(&self.history, &target);
+                    let (_, encoded_value) =
+                        open_record(self.codec(), context, &record).map_err(
+                            |error| {
+                                ConflictableTransactionError::Abort(
+                                    error
+                                        .in_stage(Stage::ValueDecode)
+                                        .at(&self.history, &target),
+                                )
+                            },
+                        )?;
+                    let (replace, metadata) = self.record(
+                        trees,
+                        encoded_key,
+                        &kept,
+                        old,
+                        encoded_value.as_deref(),
+                        stamp,
+                    )?;
+                    reverted = metadata;
+                    Ok(replace)
+                },
+            )
+        })?;
+        Ok(reverted)
+    }
+
+    /// Reverts the entry with the given `key` to the value it had in the given
+    /// `version`, by writing it as a new version, whose metadata is returned.
+    /// Reverting to a removal removes the entry. Returns `None` if the version
+    /// is not retained, or if the entry is removed and the version is a removal
+    /// too. Serializes key using a buffer from the allocation of the tree.
+    pub async fn revert(
+        &self,
+        key: &K,
+        version: u64,
+    ) -> Result<Option<Metadata>, Error>
+    where
+        K: serde::Serialize,
+        A: buffer::Allocation + Clone,
+    {
+        let mut allocation = self.tree.allocation();
+        let mut key_buf = allocation.make();
+        let result = self
+            .tree
+            .write(self.revert_raw(key, version, &mut key_buf))
+            .await
+            .map_err(|error| {
+                self.tree.failed(error, "revert", Some(key_buf.bytes()))
+            });
+        allocation.save(key_buf);
+        result
+    }
+}
+
+impl<K, V, A, S> Clone for Versioned<K, V, A, S>
+where
+    A: Clone,
+    S: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+            history: self.history.clone(),
+            retention: self.retention,
+        }
+    }
+}
+
+impl<K, V, A, S> fmt::Debug for Versioned<K, V, A, S>
+where
+    A: fmt::Debug,
+    S: Borrow<Shared>,
+{
+    fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+        fmtr.debug_struct("Versioned")
+            .field("tree", &self.tree)
+            .field("history", &self.history)
+            .field("retention", &self.retention)
+            .finish()
+    }
+}
+
+/// Read-only view of the current entries of a [`Versioned`] tree, returned by
+/// [`Versioned::current`]. Its operations are the same as those of [`Tree`].
+pub struct Current<'tree, K, V, A = buffer::DefaultPool, S = Shared> {
+    tree: &'tree Tree<K, V, A, S>,
+}
+
+impl<'tree, K, V, A, S> Current<'tree, K, V, A, S>
+where
+    S: Borrow<Shared>,
+{
+    /// Gets the value associated with the given `key`. See [`Tree::get`].
+    pub async fn get<Q>(&self, key: &Q) -> Result<Option<V>, Error>
+    where
+        for<'de> V: serde::Deserialize<'de>,
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+        A: buffer::Allocation + Clone,
+    {
+        self.tree.get(key).await
+    }
+
+    /// Gets the value associated with the given `key` along with its metadata.
+    /// See [`Tree::get_meta`].
+    pub async fn get_meta(
+        &self,
+        key: &K,
+    ) -> Result<Option<(V, Metadata)>, Error>
+    where
+        K: serde::Serialize,
+        for<'de> V: serde::Deserialize<'de>,
+        A: buffer::Allocation + Clone,
+    {
+        self.tree.get_meta(key).await
+    }
+
+    /// Gets a guard over the value associated with the given `key`. See
+    /// [`Tree::get_ref`].
+    pub async fn get_ref<Q>(&self, key: &Q) -> Result<Option<Ref<V>>, Error>
+    where
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+        A: buffer::Allocation + Clone,
+    {
+        self.tree.get_ref(key).await
+    }
+
+    /// Tests if the given key exist. See [`Tree::contains_key`].
+    pub async fn contains_key<Q>(&self, key: &Q) -> Result<bool, Error>
+    where
+        K: Borrow<Q>,
+        Q: serde::Serialize + ?Sized,
+        A: buffer::Allocation + Clone,
+    {
+        self.tree.contains_key(key).await
+    }
+
+    /// Returns a stream over every current entry. See [`Tree::iter`].
+    pub fn iter(&self) -> Iter<K, V, A>
+    where
+        for<'de> K: serde::Deserialize<'de>,
+        for<'de> V: serde::Deserialize<'de>,
+        A: Clone,
+    {
+        self.tree.iter()
+    }
+
+    /// Returns a stream over every current entry whose key is in the given
+    /// `range`. See [`Tree::range`].
+    pub fn range<R>(&self, range: R) -> Result<Iter<K, V, A>, Error>
+    where
+        for<'de> K: serde::Serialize + serde::Deserialize<'de>,
+        for<'de> V: serde::Deserialize<'de>,
+        R: RangeBounds<K>,
+        A: buffer::Allocation + Clone,
+    {
+        self.tree.range(range)
+    }
+}
+
+impl<'tree, K, V, A, S> Clone for Current<'tree, K, V, A, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'tree, K, V, A, S> Copy for Current<'tree, K, V, A, S> {}
+
+impl<'tree, K, V, A, S> fmt::Debug for Current<'tree, K, V, A, S>
+where
+    A: fmt::Debug,
+    S: Borrow<Shared>,
+{
+    fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+        fmtr.debug_struct("Current").field("tree", self.tree).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Retention, Versioned};
+    use futures::stream::TryStreamExt;
+    use std::{
+        num::NonZeroU64,
+        time::{Duration, SystemTime},
+    };
+
+    async fn versions(tree: &Versioned<u8, u64>) -> Vec<(u64, Option<u64>)> {
+        let history = tree.history(&0).unwrap();
+        let history: Vec<_> = history.try_collect().await.unwrap();
+        history
+            .into_iter()
+            .map(|(metadata, value)| (metadata.version(), value))
+            .collect()
+    }
+
+    async fn tick() -> SystemTime {
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let now = SystemTime::now();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        now
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_as_of_past_times() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Versioned::<u8, u64>::open(&db, "versioned", Retention::All)
+            .await
+            .unwrap();
+        let before = tick().await;
+        tree.insert(&0, &1).await.unwrap();
+        let first = tick().await;
+        tree.insert(&0, &2).await.unwrap();
+        let second = tick().await;
+        tree.remove(&0).await.unwrap();
+        let removed = tick().await;
+
+        assert_eq!(tree.get_as_of(&0, before).await.unwrap(), None);
+        assert_eq!(tree.get_as_of(&0, first).await.unwrap(), Some(1));
+        assert_eq!(tree.get_as_of(&0, second).await.unwrap(), Some(2));
+        assert_eq!(tree.get_as_of(&0, removed).await.unwrap(), None);
+        assert_eq!(tree.get_as_of(&1, removed).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn history_and_revert() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Versioned::<u8, u64>::open(&db, "versioned", Retention::All)
+            .await
+            .unwrap();
+        tree.insert(&0, &1).await.unwrap();
+        tree.insert(&0, &2).await.unwrap();
+        tree.remove(&0).await.unwrap();
+        assert_eq!(
+            versions(&tree).await,
+            vec![(1, Some(1)), (2, Some(2)), (3, None)]
+        );
+
+        let metadata = tree.revert(&0, 1).await.unwrap().unwrap();
+        assert_eq!(metadata.version(), 4);
+        assert_eq!(tree.current().get(&0).await.unwrap(), Some(1));
+        let (_, current) = tree.current().get_meta(&0).await.unwrap().unwrap();
+        assert_eq!(current, metadata);
+
+        let metadata = tree.revert(&0, 3).await.unwrap().unwrap();
+        assert_eq!(metadata.version(), 5);
+        assert_eq!(tree.current().get(&0).await.unwrap(), None);
+        assert_eq!(tree.revert(&0, 3).await.unwrap(), None);
+        assert_eq!(tree.revert(&0, 9).await.unwrap(), None);
+        assert_eq!(
+            versions(&tree).await,
+            vec![
+                (1, Some(1)),
+                (2, Some(2)),
+                (3, None),
+                (4, Some(1)),
+                (5, None)
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retention_prunes_history() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let three = Retention::Last(NonZeroU64::new(3).unwrap());
+        let tree = Versioned::<u8, u64>::open(&db, "versioned", three)
+            .await
+            .unwrap();
+        for value in 1..=5 {
+            tree.insert(&0, &value).await.unwrap();
+        }
+        tree.insert(&1, &1).await.unwrap();
+        assert_eq!(
+            versions(&tree).await,
+            vec![(3, Some(3)), (4, Some(4)), (5, Some(5))]
+        );
+        assert_eq!(tree.revert(&0, 2).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reopen_with_smaller_retention() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = Versioned::<u8, u64>::open(&db, "versioned", Retention::All)
+            .await
+            .unwrap();
+        for value in 1..=5 {
+            tree.insert(&0, &value).await.unwrap();
+        }
+
+        let two = Retention::Last(NonZeroU64::new(2).unwrap());
+        let tree = Versioned::<u8, u64>::open(&db, "versioned", two)
+            .await
+            .unwrap();
+        assert_eq!(versions(&tree).await.len(), 5);
+        tree.insert(&0, &6).await.unwrap();
+        assert_eq!(versions(&tree).await, vec![(5, Some(5)), (6, Some(6))]);
+        tree.insert(&0, &7).await.unwrap();
+        assert_eq!(versions(&tree).await, vec![(6, Some(6)), (7, Some(7))]);
+    }
+}